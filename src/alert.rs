@@ -0,0 +1,37 @@
+use crate::event::Event;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AlertKind {
+    FermentationComplete,
+    StuckFermentation,
+    Threshold,
+    Recovered,
+    Offline,
+    Online,
+}
+
+/// Something that happened to a device, that someone probably wants to be
+/// told about. Alerts are sent to emitters separately from the readings.
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub kind: AlertKind,
+    pub message: String,
+    pub rule: Option<String>, // The name of the alert rule, if any
+    #[serde(skip)]
+    pub event: Event, // The reading that caused the alert
+}
+
+impl Alert {
+    pub fn new(kind: AlertKind, message: String, event: &Event) -> Alert {
+        let mut event = event.clone();
+        event.alerts.clear();
+        Alert {
+            kind,
+            message,
+            rule: None,
+            event,
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use crate::config_edit::{device_table, write_config};
+use crate::devices::check_device;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, Utc};
+use clap::Clap;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use toml_edit::{value, DocumentMut, Item, Table};
+
+/// A beer being fermented, from a `[device.<color or MAC>.batch]` section.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Batch {
+    pub name: String,
+    pub style: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_datetime")]
+    pub start: Option<DateTime<Utc>>,
+    #[serde(default, deserialize_with = "deserialize_datetime")]
+    pub end: Option<DateTime<Utc>>,
+    pub og: Option<f64>,
+    #[serde(rename(deserialize = "target-fg"))]
+    pub target_fg: Option<f64>,
+}
+
+impl Batch {
+    /// Whether the batch had started, and not yet ended, at `timestamp`.
+    pub fn is_active(&self, timestamp: &DateTime<Utc>) -> bool {
+        self.start.is_none_or(|start| start <= *timestamp)
+            && self.end.is_none_or(|end| *timestamp < end)
+    }
+}
+
+/// Dates can be written as TOML datetimes or as strings, and either can
+/// leave out the time or the offset, which then default to midnight and
+/// UTC.
+fn deserialize_datetime<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Datetime {
+        Toml(toml::value::Datetime),
+        String(String),
+    }
+    let datetime = match Option::<Datetime>::deserialize(deserializer)? {
+        None => return Ok(None),
+        Some(Datetime::Toml(datetime)) => datetime.to_string(),
+        Some(Datetime::String(datetime)) => datetime,
+    };
+    parse_datetime(&datetime)
+        .map(Some)
+        .ok_or_else(|| de::Error::custom(format!("Invalid date {}", datetime)))
+}
+
+fn parse_datetime(datetime: &str) -> Option<DateTime<Utc>> {
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(datetime) {
+        return Some(datetime.with_timezone(&Utc));
+    }
+    let naive = NaiveDateTime::parse_from_str(datetime, "%Y-%m-%dT%H:%M:%S%.f")
+        .or_else(|_| NaiveDateTime::parse_from_str(datetime, "%Y-%m-%d %H:%M:%S%.f"))
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(datetime, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })?;
+    Some(DateTime::from_naive_utc_and_offset(naive, Utc))
+}
+
+#[derive(Clap, Debug)]
+pub struct BatchOpts {
+    #[clap(subcommand)]
+    command: BatchCommand,
+}
+
+#[derive(Clap, Debug)]
+enum BatchCommand {
+    /// Start a new batch on a tilt
+    Start(StartOpts),
+    /// End the batch on a tilt
+    End(EndOpts),
+}
+
+#[derive(Clap, Debug, Clone)]
+struct StartOpts {
+    /// The color or MAC address of the tilt
+    device: String,
+    /// The name of the batch
+    name: String,
+    /// The style of the beer
+    #[clap(long)]
+    style: Option<String>,
+    /// The original gravity, if it's already known
+    #[clap(long)]
+    og: Option<f64>,
+    /// The final gravity the beer should reach
+    #[clap(long)]
+    target_fg: Option<f64>,
+}
+
+#[derive(Clap, Debug)]
+struct EndOpts {
+    /// The color or MAC address of the tilt
+    device: String,
+}
+
+pub fn run(opts: &BatchOpts, config_path: &str, config_str: &str) -> Result<()> {
+    let now = Utc::now();
+    let (config, message) = match &opts.command {
+        BatchCommand::Start(start) => (
+            start_batch(config_str, start, &now)?,
+            format!("Started {} on the {} tilt", start.name, start.device),
+        ),
+        BatchCommand::End(end) => (
+            end_batch(config_str, &end.device, &now)?,
+            format!("Ended the batch on the {} tilt", end.device),
+        ),
+    };
+    write_config(config_path, &config)
+        .with_context(|| format!("Couldn't write batch to {}", config_path))?;
+    println!("{}. Restart tilted for it to take effect.", message);
+    Ok(())
+}
+
+/// The batch in the config for a device, if there is one.
+fn current(config: &mut DocumentMut, device: &str) -> Result<Option<Batch>> {
+    match device_table(config, device)?.get("batch") {
+        None => Ok(None),
+        Some(batch) => {
+            let batch = toml::from_str(&format!("{}", batch_document(batch)))
+                .with_context(|| format!("Invalid batch for device {}", device))?;
+            Ok(Some(batch))
+        }
+    }
+}
+
+/// A document with just the contents of the batch table, to parse.
+fn batch_document(batch: &Item) -> DocumentMut {
+    let mut document = DocumentMut::new();
+    if let Some(batch) = batch.as_table_like() {
+        for (key, item) in batch.iter() {
+            document.insert(key, item.clone());
+        }
+    }
+    document
+}
+
+fn format_datetime(datetime: &DateTime<Utc>) -> Result<toml_edit::Datetime> {
+    Ok(datetime
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+        .parse()?)
+}
+
+/// Put a new batch into the `[device.<device>.batch]` section, replacing
+/// any batch that has ended.
+fn start_batch(config_str: &str, opts: &StartOpts, now: &DateTime<Utc>) -> Result<String> {
+    check_device(&opts.device)?;
+    let mut config: DocumentMut = config_str.parse()?;
+    if let Some(batch) = current(&mut config, &opts.device)? {
+        if batch.end.is_none() {
+            bail!(
+                "The {} tilt already has the batch {}, end it first",
+                opts.device,
+                batch.name
+            );
+        }
+    }
+    let mut batch = Table::new();
+    batch.insert("name", value(&opts.name));
+    if let Some(style) = &opts.style {
+        batch.insert("style", value(style));
+    }
+    batch.insert("start", value(format_datetime(now)?));
+    if let Some(og) = opts.og {
+        batch.insert("og", value(og));
+    }
+    if let Some(target_fg) = opts.target_fg {
+        batch.insert("target-fg", value(target_fg));
+    }
+    device_table(&mut config, &opts.device)?.insert("batch", Item::Table(batch));
+    Ok(config.to_string())
+}
+
+fn end_batch(config_str: &str, device: &str, now: &DateTime<Utc>) -> Result<String> {
+    check_device(device)?;
+    let mut config: DocumentMut = config_str.parse()?;
+    match current(&mut config, device)? {
+        None => bail!("The {} tilt has no batch", device),
+        Some(batch) if batch.end.is_some() => {
+            bail!("The batch {} has already ended", batch.name)
+        }
+        Some(_) => {}
+    }
+    let batch = device_table(&mut config, device)?
+        .get_mut("batch")
+        .and_then(|batch| batch.as_table_like_mut())
+        .context("batch in the config is not a table")?;
+    batch.insert("end", value(format_datetime(now)?));
+    Ok(config.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::DeviceConfig;
+    use std::collections::HashMap;
+
+    #[derive(Deserialize)]
+    struct Config {
+        device: HashMap<String, DeviceConfig>,
+    }
+
+    fn parse(config: &str) -> Batch {
+        let config: Config = toml::from_str(config).unwrap();
+        config.device["red"].batch.clone().unwrap()
+    }
+
+    #[test]
+    fn start_and_end() -> Result<()> {
+        let config = "[log]\nemitter = \"log\"\n";
+        let start = "2021-03-01T12:00:00Z".parse()?;
+        let opts = StartOpts {
+            device: "red".to_string(),
+            name: "IPA #42".to_string(),
+            style: Some("IPA".to_string()),
+            og: Some(1.062),
+            target_fg: None,
+        };
+        let config = start_batch(config, &opts, &start)?;
+        assert_eq!(
+            config,
+            r#"[log]
+emitter = "log"
+
+[device.red.batch]
+name = "IPA #42"
+style = "IPA"
+start = 2021-03-01T12:00:00Z
+og = 1.062
+"#
+        );
+        assert!(start_batch(&config, &opts, &start).is_err());
+
+        let end = "2021-03-15T12:00:00Z".parse()?;
+        let config = end_batch(&config, "RED", &end)?;
+        let batch = parse(&config);
+        assert!(batch.is_active(&"2021-03-02T00:00:00Z".parse()?));
+        assert!(!batch.is_active(&end));
+        assert!(end_batch(&config, "red", &end).is_err());
+        start_batch(&config, &opts, &end)?;
+
+        // Typos would start a batch on a device that doesn't exist
+        for device in ["rde", "AA:BB:CC:DD:EE", "AA:BB:CC:DD:EE:GG"] {
+            let opts = StartOpts {
+                device: device.to_string(),
+                ..opts.clone()
+            };
+            assert!(start_batch(&config, &opts, &start).is_err());
+        }
+        let opts = StartOpts {
+            device: "aa:bb:cc:dd:ee:ff".to_string(),
+            ..opts
+        };
+        start_batch(&config, &opts, &start)?;
+        Ok(())
+    }
+
+    #[test]
+    fn dates() {
+        let batch = parse(
+            r#"[device.red.batch]
+name = "Stout"
+start = 2021-03-01
+end = "2021-03-15 18:00:00"
+"#,
+        );
+        assert_eq!(batch.start, Some("2021-03-01T00:00:00Z".parse().unwrap()));
+        assert_eq!(batch.end, Some("2021-03-15T18:00:00Z".parse().unwrap()));
+    }
+}
@@ -277,7 +277,7 @@ pub fn open() -> Result<RawFd, io::Error> {
 }
 
 // TODO: this bit flipping should be on the type level
-pub fn enable_le_scan(stream: &mut UnixStream) -> Result<(), io::Error> {
+fn set_le_scan(stream: &mut UnixStream, enable: bool) -> Result<(), io::Error> {
     let ogf = Ogf::LeCtl;
     let ocf = Ocf::LeCtl(LeCtl::SetScanEnable);
     let opcode: Opcode = (ogf, ocf).into();
@@ -287,22 +287,50 @@ pub fn enable_le_scan(stream: &mut UnixStream) -> Result<(), io::Error> {
     buf[0..4].copy_from_slice(&hci_type);
     buf[4..6].copy_from_slice(&opcode);
     buf[6] = 2; // len
-    buf[7] = 1; // enable?
+    buf[7] = enable as u8; // enable?
     buf[8] = 1; // repeat?
     stream.write_all(&buf)?;
     Ok(())
 }
 
+pub fn enable_le_scan(stream: &mut UnixStream) -> Result<(), io::Error> {
+    set_le_scan(stream, true)
+}
+
+pub fn disable_le_scan(stream: &mut UnixStream) -> Result<(), io::Error> {
+    set_le_scan(stream, false)
+}
+
+/// Wait up to `timeout` milliseconds for something to read. Returns
+/// whether there is.
+pub fn poll(stream: &UnixStream, timeout: i32) -> Result<bool, io::Error> {
+    let mut fd = libc::pollfd {
+        fd: stream.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let ready = unsafe { libc::poll(&mut fd, 1, timeout) };
+    if ready < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::Interrupted {
+            return Ok(false);
+        }
+        return Err(err);
+    }
+    Ok(ready > 0)
+}
+
 pub fn get_filter(stream: &UnixStream) -> Result<HciFilter, io::Error> {
-    let filter = HciFilter::default();
+    let mut filter = HciFilter::default();
+    let mut len = std::mem::size_of::<HciFilter>() as libc::socklen_t;
 
     if unsafe {
-        libc::setsockopt(
+        libc::getsockopt(
             stream.as_raw_fd(),
             Sol::HCI as i32,
             HciSocketOption::Filter as i32,
-            &filter as *const HciFilter as *const c_void,
-            std::mem::size_of::<HciFilter>() as u32,
+            &mut filter as *mut HciFilter as *mut c_void,
+            &mut len,
         )
     } < 0
     {
@@ -1,12 +1,18 @@
-use crate::bluez::{enable_le_scan, get_filter, open, set_filter, HciEvent, HciFilter, HciType};
+use crate::bluez::{
+    disable_le_scan, enable_le_scan, get_filter, open, poll, set_filter, HciEvent, HciFilter,
+    HciType,
+};
 use crate::bt_parsing::bt_parser;
-use crate::event::{Color, Dispatcher, Event};
+use crate::event::{Color, Event};
 use crate::ibeacon_parsing::{ibeacon_parser, IBeacon};
+use crate::shutdown::Shutdown;
 use anyhow::{Context, Result};
+use chrono::Utc;
 use std::{
     convert::{TryFrom, TryInto},
     io::Read,
     os::unix::{io::FromRawFd, net::UnixStream},
+    sync::mpsc::Sender,
     time::Duration,
 };
 use thiserror::Error;
@@ -73,32 +79,52 @@ impl TryFrom<IBeacon> for Event {
     type Error = EventError;
 
     fn try_from(ibeacon: IBeacon) -> Result<Event, EventError> {
-        Ok(Event {
-            color: ibeacon.proximity_uuid.try_into()?,
-            temperature: ibeacon.major,
-            gravity: (ibeacon.minor as f32) / 1000.,
-        })
+        Ok(Event::new(
+            ibeacon.proximity_uuid.try_into()?,
+            None,
+            Utc::now(),
+            f64::from(ibeacon.major),
+            f64::from(ibeacon.minor) / 1000.,
+        ))
     }
 }
 
-pub fn run(dispatcher: &Dispatcher) -> Result<()> {
+pub fn run(sender: &Sender<Event>, shutdown: &Shutdown) -> Result<()> {
     let fd = open()?;
 
     let stream = unsafe { UnixStream::from_raw_fd(fd) };
 
-    main_loop(stream, &dispatcher).map_err(|e| {
+    main_loop(stream, sender, shutdown).map_err(|e| {
         unsafe { libc::close(fd) };
         e
     })?;
     Ok(())
 }
 
-fn inner(stream: &mut UnixStream, buf: &mut [u8]) -> Result<usize, anyhow::Error> {
+/// Wait until there's something to read, checking regularly whether
+/// tilted is shutting down. Returns `false` if it is.
+fn wait(stream: &UnixStream, shutdown: &Shutdown) -> Result<bool> {
+    while !shutdown.requested() {
+        if poll(stream, 100)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn inner(
+    stream: &mut UnixStream,
+    buf: &mut [u8],
+    shutdown: &Shutdown,
+) -> Result<Option<usize>, anyhow::Error> {
     set_filter(
         &stream,
         HciFilter::new(HciType::EventPkt, HciEvent::LeMetaEvent),
     )?;
     enable_le_scan(stream)?;
+    if !wait(stream, shutdown)? {
+        return Ok(None);
+    }
     stream
         .read_exact(&mut buf[..3])
         .context("Couldn't read header from bluetooth socket")?;
@@ -106,27 +132,40 @@ fn inner(stream: &mut UnixStream, buf: &mut [u8]) -> Result<usize, anyhow::Error
     stream
         .read_exact(&mut buf[3..len])
         .context("Couldn't read body from bluetooth socket")?;
-    Ok(len)
+    Ok(Some(len))
 }
 
-fn main_loop(mut stream: UnixStream, dispatcher: &Dispatcher) -> Result<(), anyhow::Error> {
+fn main_loop(
+    mut stream: UnixStream,
+    sender: &Sender<Event>,
+    shutdown: &Shutdown,
+) -> Result<(), anyhow::Error> {
     let mut buf = [0u8; 258];
-    loop {
-        std::thread::sleep(Duration::from_secs(2));
+    'scan: while !shutdown.sleep(Duration::from_secs(2)) {
         let old_filter = get_filter(&stream)?;
-        let len = inner(&mut stream, &mut buf).map_err(|err| {
+        let len = inner(&mut stream, &mut buf, shutdown).map_err(|err| {
             let _ = set_filter(&stream, old_filter);
             err
         })?;
         set_filter(&stream, old_filter)?;
+        let len = match len {
+            Some(len) => len,
+            None => break,
+        };
         if let Ok((_, events)) = bt_parser()(&buf[..len]) {
-            for event in events {
-                if let Ok((_, ibeacon)) = ibeacon_parser()(&event.data) {
-                    if let Ok(event) = ibeacon.try_into() {
-                        dispatcher.dispatch(&event);
+            for le_event in events {
+                if let Ok((_, ibeacon)) = ibeacon_parser()(&le_event.data) {
+                    if let Ok(mut event) = Event::try_from(ibeacon) {
+                        event.mac = Some(le_event.mac());
+                        if sender.send(event).is_err() {
+                            // Nobody is listening anymore, so we're done
+                            break 'scan;
+                        }
                     }
                 }
             }
         }
     }
+    disable_le_scan(&mut stream).context("Couldn't stop scanning")?;
+    Ok(())
 }
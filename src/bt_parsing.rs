@@ -41,6 +41,19 @@ pub struct LeEvent {
     rssi: i8,
 }
 
+impl LeEvent {
+    /// The device address in the usual human readable form. It's sent
+    /// least significant byte first, so it needs to be reversed.
+    pub fn mac(&self) -> String {
+        self.address
+            .iter()
+            .rev()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, FromPrimitive)]
 enum EventType {
@@ -0,0 +1,157 @@
+use anyhow::{bail, Result};
+use serde::Deserialize;
+
+/// Maps what a device measures to what it should have measured.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+#[serde(untagged)]
+pub enum Calibration {
+    /// Add a constant to every reading.
+    Offset { offset: f64 },
+    /// `[measured, actual]` pairs, interpolated linearly between the
+    /// points, and extrapolated from the outermost points.
+    Points { points: Vec<[f64; 2]> },
+    /// Coefficients of a polynomial of the measured value, constant term
+    /// first.
+    Polynomial { polynomial: Vec<f64> },
+}
+
+impl Calibration {
+    pub fn validate(&self) -> Result<()> {
+        let values = match self {
+            Calibration::Offset { offset } => vec![*offset],
+            Calibration::Points { points } => points.iter().flatten().copied().collect(),
+            Calibration::Polynomial { polynomial } => polynomial.clone(),
+        };
+        if values.iter().any(|value| !value.is_finite()) {
+            bail!("calibration values must be numbers");
+        }
+        match self {
+            Calibration::Offset { .. } => {}
+            Calibration::Points { points } => {
+                if points.is_empty() {
+                    bail!("calibration needs at least one point");
+                }
+                let mut measured = points.iter().map(|p| p[0]).collect::<Vec<_>>();
+                measured.sort_by(f64::total_cmp);
+                if measured.windows(2).any(|w| w[0] == w[1]) {
+                    bail!("calibration points must have different measured values");
+                }
+            }
+            Calibration::Polynomial { polynomial } => {
+                if polynomial.is_empty() {
+                    bail!("calibration polynomial needs at least one coefficient");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply(&self, measured: f64) -> f64 {
+        match self {
+            Calibration::Offset { offset } => measured + offset,
+            Calibration::Points { points } => interpolate(points, measured),
+            Calibration::Polynomial { polynomial } => polynomial
+                .iter()
+                .rev()
+                .fold(0., |sum, coefficient| sum * measured + coefficient),
+        }
+    }
+}
+
+fn interpolate(points: &[[f64; 2]], measured: f64) -> f64 {
+    let mut points = points.to_vec();
+    points.sort_by(|a, b| a[0].total_cmp(&b[0]));
+    if points.len() == 1 {
+        return measured + points[0][1] - points[0][0];
+    }
+    // Use the segment the value falls in, or the closest one if it's
+    // outside of the calibrated range
+    let segment = points
+        .windows(2)
+        .position(|w| measured < w[1][0])
+        .unwrap_or(points.len() - 2);
+    let [x0, y0] = points[segment];
+    let [x1, y1] = points[segment + 1];
+    y0 + (measured - x0) * (y1 - y0) / (x1 - x0)
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CalibrationConfig {
+    pub temperature: Option<Calibration>,
+    pub gravity: Option<Calibration>,
+}
+
+impl CalibrationConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(temperature) = &self.temperature {
+            temperature.validate()?;
+        }
+        if let Some(gravity) = &self.gravity {
+            gravity.validate()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn offset() {
+        let calibration = Calibration::Offset { offset: -0.002 };
+        assert!((calibration.apply(1.050) - 1.048).abs() < 1e-9);
+    }
+
+    #[test]
+    fn points() {
+        let calibration = Calibration::Points {
+            points: vec![[1.002, 1.000], [1.062, 1.060], [1.032, 1.031]],
+        };
+        assert!(calibration.validate().is_ok());
+        assert!((calibration.apply(1.002) - 1.000).abs() < 1e-9);
+        assert!((calibration.apply(1.017) - 1.0155).abs() < 1e-9);
+        assert!((calibration.apply(1.047) - 1.0455).abs() < 1e-9);
+        // Extrapolated from the closest segment
+        assert!((calibration.apply(0.998) - 0.9958667).abs() < 1e-6);
+        assert!((calibration.apply(1.072) - 1.0696667).abs() < 1e-6);
+
+        let nan = Calibration::Points {
+            points: vec![[1.002, 1.000], [f64::NAN, 1.060]],
+        };
+        assert!(nan.validate().is_err());
+        assert!(Calibration::Offset {
+            offset: f64::INFINITY
+        }
+        .validate()
+        .is_err());
+
+        let single = Calibration::Points {
+            points: vec![[70., 68.]],
+        };
+        assert_eq!(single.apply(60.), 58.);
+    }
+
+    #[test]
+    fn polynomial() {
+        let calibration = Calibration::Polynomial {
+            polynomial: vec![1., 2., 3.],
+        };
+        assert_eq!(calibration.apply(2.), 1. + 2. * 2. + 3. * 4.);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Calibration::Points { points: vec![] }.validate().is_err());
+        assert!(Calibration::Points {
+            points: vec![[1., 1.], [1., 2.]]
+        }
+        .validate()
+        .is_err());
+        assert!(Calibration::Polynomial { polynomial: vec![] }
+            .validate()
+            .is_err());
+    }
+}
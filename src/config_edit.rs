@@ -0,0 +1,46 @@
+use anyhow::{Context, Result};
+use std::path::Path;
+use toml_edit::{DocumentMut, Item, Table, TableLike};
+
+/// The table at `key`, creating it if it's missing. Implicit tables don't
+/// get a header of their own, so they're used for tables that only hold
+/// other tables.
+pub fn table<'a>(
+    parent: &'a mut dyn TableLike,
+    key: &str,
+    implicit: bool,
+) -> Result<&'a mut dyn TableLike> {
+    parent
+        .entry(key)
+        .or_insert_with(|| {
+            let mut table = Table::new();
+            table.set_implicit(implicit);
+            Item::Table(table)
+        })
+        .as_table_like_mut()
+        .with_context(|| format!("{} in the config is not a table", key))
+}
+
+/// The `[device.<device>]` table, creating it if it's missing. Device keys
+/// aren't case sensitive, so an existing section is used whatever its case.
+pub fn device_table<'a>(
+    config: &'a mut DocumentMut,
+    device: &str,
+) -> Result<&'a mut dyn TableLike> {
+    let devices = table(config.as_table_mut(), "device", true)?;
+    let key = devices
+        .iter()
+        .map(|(key, _)| key.to_string())
+        .find(|key| key.eq_ignore_ascii_case(device))
+        .unwrap_or_else(|| device.to_string());
+    table(devices, &key, true)
+}
+
+/// Write the config to a temporary file first, so a crash or a full disk
+/// can't leave it half written.
+pub fn write_config(config_path: &str, config: &str) -> Result<()> {
+    let temporary = Path::new(config_path).with_extension("tmp");
+    std::fs::write(&temporary, config)?;
+    std::fs::rename(&temporary, config_path)?;
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use crate::batch::Batch;
+use crate::calibration::CalibrationConfig;
+use crate::event::{Color, Event};
+use crate::processors::temperature_correction::TemperatureCorrection;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Settings for one device, from a `[device.<color or MAC>]` section.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub name: Option<String>,
+    #[serde(default)]
+    pub calibration: CalibrationConfig,
+    #[serde(rename = "temperature-correction")]
+    pub temperature_correction: Option<TemperatureCorrection>,
+    pub og: Option<f64>,
+    pub batch: Option<Batch>,
+}
+
+/// Make sure a device is a tilt color or a MAC address, like
+/// `AA:BB:CC:DD:EE:FF`, since a typo would quietly match nothing.
+pub fn check_device(device: &str) -> Result<()> {
+    let is_mac = device.split(':').count() == 6
+        && device
+            .split(':')
+            .all(|byte| byte.len() == 2 && byte.chars().all(|c| c.is_ascii_hexdigit()));
+    if !is_mac && device.parse::<Color>().is_err() {
+        bail!("{} is neither a tilt color nor a MAC address", device);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Devices {
+    devices: HashMap<String, DeviceConfig>,
+}
+
+impl Devices {
+    pub fn new(devices: &HashMap<String, DeviceConfig>) -> Result<Devices> {
+        for (key, device) in devices {
+            check_device(key)?;
+            device
+                .calibration
+                .validate()
+                .with_context(|| format!("Invalid calibration for device {}", key))?;
+        }
+        Ok(Devices {
+            devices: devices
+                .iter()
+                .map(|(key, device)| (key.to_lowercase(), device.clone()))
+                .collect(),
+        })
+    }
+
+    /// The settings for the device that sent an event. Settings for the
+    /// device's MAC address win over settings for its color.
+    pub fn get(&self, event: &Event) -> Option<&DeviceConfig> {
+        event
+            .mac
+            .as_ref()
+            .and_then(|mac| self.devices.get(&mac.to_lowercase()))
+            .or_else(|| {
+                let color: &'static str = (&event.color).into();
+                self.devices.get(color)
+            })
+    }
+}
@@ -0,0 +1,1217 @@
+use crate::alert::Alert;
+use crate::emitters::{Emitter, EmitterError, Outcome};
+use crate::event::{Color, Event, Reading};
+use crate::expression::Expression;
+use crate::health::{Breaker, BreakerOptions, Health, State};
+use crate::processors::{metrics, Pipeline};
+use crate::queue::{BoundedQueue, Overflow, Popped, Pushed};
+use crate::spool::{Entry, RetryOptions, Spool};
+use crate::units::TemperatureUnit;
+use crate::watchdog::DeviceStatus;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+/// An emitter, along with the settings that work the same for every kind
+/// of emitter.
+#[derive(Debug)]
+pub struct Module {
+    pub name: String,
+    pub emitter: Box<dyn Emitter>,
+    pub smoothing: Option<String>,
+    pub events: Events,
+    pub include: Option<DeviceFilter>,
+    pub exclude: Option<DeviceFilter>,
+    pub changes: Option<ChangeDetection>,
+    pub pipeline: Pipeline,
+    pub when: Option<Expression>,
+    pub fields: BTreeMap<String, Expression>,
+    pub failing: bool, // Whether the expressions failed for the last event
+    pub queue_size: usize,
+    pub overflow: Overflow,
+    pub retry: Option<RetryOptions>,
+    pub breaker: BreakerOptions,
+}
+
+/// Matches devices by any of their identities. An empty filter matches
+/// nothing.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceFilter {
+    #[serde(default)]
+    colors: Vec<Color>,
+    #[serde(default)]
+    macs: Vec<String>,
+    #[serde(default)]
+    names: Vec<String>,
+    #[serde(default)]
+    batches: Vec<String>,
+}
+
+impl DeviceFilter {
+    pub fn matches(&self, event: &Event) -> bool {
+        self.colors.contains(&event.color)
+            || event.mac.as_ref().is_some_and(|mac| {
+                self.macs
+                    .iter()
+                    .any(|filter| filter.eq_ignore_ascii_case(mac))
+            })
+            || event
+                .name
+                .as_ref()
+                .is_some_and(|name| self.names.contains(name))
+            || event
+                .batch
+                .as_ref()
+                .is_some_and(|batch| self.batches.contains(&batch.name))
+    }
+}
+
+/// Which events an emitter gets.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Events {
+    Readings,
+    Alerts,
+    #[default]
+    Both,
+}
+
+/// How much a reading must differ from the last one sent to be sent.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Deadband {
+    #[serde(default)]
+    gravity: f64,
+    #[serde(default)]
+    temperature: f64,
+    #[serde(rename = "temperature-unit")]
+    #[serde(default)]
+    temperature_unit: TemperatureUnit,
+}
+
+/// Holds back readings that haven't changed by more than the deadband
+/// since the last reading sent from the same device, unless it's been
+/// `heartbeat` since then.
+#[derive(Debug)]
+pub struct ChangeDetection {
+    deadband: Deadband,
+    heartbeat: Option<chrono::Duration>,
+    sent: Mutex<HashMap<String, (DateTime<Utc>, Reading)>>, // By device
+}
+
+impl ChangeDetection {
+    pub fn new(deadband: Option<Deadband>, heartbeat: Option<Duration>) -> Result<ChangeDetection> {
+        Ok(ChangeDetection {
+            deadband: deadband.unwrap_or_default(),
+            heartbeat: heartbeat.map(chrono::Duration::from_std).transpose()?,
+            sent: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn changed(&self, event: &Event) -> bool {
+        let sent = self.sent.lock().unwrap();
+        let (timestamp, reading) = match sent.get(&event.device()) {
+            Some(sent) => sent,
+            None => return true,
+        };
+        let exceeds = |difference: f64, deadband: f64| {
+            // A little slack, so a deadband of 0.001 lets 1.049 -> 1.050
+            // through despite rounding
+            difference > 1e-9 && difference >= deadband - 1e-9
+        };
+        let temperature = match self.deadband.temperature_unit {
+            TemperatureUnit::Fahrenheit => self.deadband.temperature,
+            TemperatureUnit::Celsius => self.deadband.temperature * 9. / 5.,
+        };
+        exceeds(
+            (event.gravity - reading.gravity).abs(),
+            self.deadband.gravity,
+        ) || exceeds((event.temperature - reading.temperature).abs(), temperature)
+            || self
+                .heartbeat
+                .is_some_and(|heartbeat| event.timestamp - *timestamp >= heartbeat)
+    }
+
+    fn sent(&self, event: &Event) {
+        let reading = Reading {
+            temperature: event.temperature,
+            gravity: event.gravity,
+        };
+        let mut sent = self.sent.lock().unwrap();
+        sent.insert(event.device(), (event.timestamp, reading));
+    }
+}
+
+impl Module {
+    /// The event as this module's emitter should see it.
+    fn view(&self, mut event: Event) -> Event {
+        let smoothed = self
+            .smoothing
+            .as_ref()
+            .and_then(|smoothing| event.smoothed.get(smoothing))
+            .copied();
+        if let Some(smoothed) = smoothed {
+            event.unsmoothed = Some(Reading {
+                temperature: event.temperature,
+                gravity: event.gravity,
+            });
+            event.temperature = smoothed.temperature;
+            event.gravity = smoothed.gravity;
+            // So the ABV goes with the gravity that's sent
+            metrics::og_metrics(&mut event);
+        }
+        event
+    }
+}
+
+/// Which messages a module's emitter wants. They're picked out before
+/// they're queued, so the ones it doesn't want don't take up room.
+#[derive(Debug, Clone)]
+struct Filter {
+    events: Events,
+    include: Option<DeviceFilter>,
+    exclude: Option<DeviceFilter>,
+}
+
+impl Filter {
+    fn new(module: &Module) -> Filter {
+        Filter {
+            events: module.events,
+            include: module.include.clone(),
+            exclude: module.exclude.clone(),
+        }
+    }
+
+    /// Whether the emitter wants events from the device that sent this
+    /// one.
+    fn wants(&self, event: &Event) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.matches(event))
+            && !self
+                .exclude
+                .as_ref()
+                .is_some_and(|exclude| exclude.matches(event))
+    }
+
+    /// The part of a message the emitter wants, if any. Readings are let
+    /// through even if the emitter only wants alerts, since its own
+    /// pipeline might raise some.
+    fn select(&self, message: Message) -> Option<Message> {
+        match message {
+            Message::Reading(event) => self.wants(&event).then_some(Message::Reading(event)),
+            Message::Alert(alert) => (self.events != Events::Readings && self.wants(&alert.event))
+                .then_some(Message::Alert(alert)),
+            Message::Status(devices) => {
+                if self.events == Events::Alerts {
+                    return None;
+                }
+                let devices = devices
+                    .iter()
+                    .filter(|device| self.wants(&device.last))
+                    .cloned()
+                    .collect();
+                Some(Message::Status(Arc::new(devices)))
+            }
+        }
+    }
+}
+
+/// What a worker hands to its emitter.
+#[derive(Debug, Clone)]
+enum Message {
+    Reading(Arc<Event>),
+    Alert(Arc<Alert>),
+    Status(Arc<Vec<DeviceStatus>>),
+}
+
+impl Module {
+    /// What to send for a message, if anything.
+    fn prepare(&mut self, message: &Message) -> Vec<Entry> {
+        match message {
+            Message::Reading(event) => {
+                // The alerts of the main pipeline are sent on their own, so
+                // only the ones from this emitter's pipeline are left
+                let mut event = Event {
+                    alerts: vec![],
+                    ..(**event).clone()
+                };
+                event = match self.pipeline.process(event) {
+                    Some(event) => event,
+                    None => return vec![],
+                };
+                let alerts = std::mem::take(&mut event.alerts);
+                let mut entries = vec![];
+                if self.events != Events::Alerts {
+                    let mut event = self.view(event);
+                    if self.select(&mut event)
+                        && self
+                            .changes
+                            .as_ref()
+                            .is_none_or(|changes| changes.changed(&event))
+                    {
+                        entries.push(Entry::Reading { event });
+                    }
+                }
+                if self.events != Events::Readings {
+                    for mut alert in alerts {
+                        if self.select(&mut alert.event) {
+                            entries.push(Entry::alert(&alert));
+                        }
+                    }
+                }
+                entries
+            }
+            Message::Alert(alert) => {
+                let mut alert = (**alert).clone();
+                if !self.select(&mut alert.event) {
+                    return vec![];
+                }
+                vec![Entry::alert(&alert)]
+            }
+            Message::Status(_) => vec![],
+        }
+    }
+
+    /// Work out the computed fields of an event, and whether it passes
+    /// `when`. Events the expressions can't be worked out for aren't sent.
+    fn select(&mut self, event: &mut Event) -> bool {
+        let result = self.compute(event);
+        // Only warn when it starts failing, not for every event
+        let failing = result.is_err();
+        let selected = match result {
+            Ok(selected) => {
+                if self.failing {
+                    info!("The expressions of {} work again", self.name);
+                }
+                selected
+            }
+            Err(e) if self.failing => {
+                debug!("Not sending an event to {}: {:#}", self.name, e);
+                false
+            }
+            Err(e) => {
+                warn!(
+                    "Not sending events to {} until its expressions work: {:#}",
+                    self.name, e
+                );
+                false
+            }
+        };
+        self.failing = failing;
+        selected
+    }
+
+    fn compute(&self, event: &mut Event) -> Result<bool> {
+        if !self.fields.is_empty() {
+            // Fields are worked out from the event, not from each other
+            let variables = event.variables()?;
+            for (name, field) in &self.fields {
+                let value = field
+                    .evaluate(&variables)
+                    .with_context(|| format!("Couldn't work out {}", name))?;
+                event.fields.insert(name.clone(), value.into());
+            }
+        }
+        match &self.when {
+            Some(when) => when
+                .is_true(&event.variables()?)
+                .with_context(|| format!("Couldn't work out {:?}", when.to_string())),
+            None => Ok(true),
+        }
+    }
+
+    fn send(&self, entry: &Entry) -> Result<Outcome, EmitterError> {
+        match entry {
+            Entry::Reading { event } => self.emitter.emit(event),
+            Entry::Alert {
+                kind,
+                message,
+                rule,
+                event,
+            } => self
+                .emitter
+                .alert(&Alert {
+                    kind: *kind,
+                    message: message.clone(),
+                    rule: rule.clone(),
+                    event: event.clone(),
+                })
+                .map(|()| Outcome::Sent),
+        }
+    }
+
+    /// The device status is sent straight away, since it's only worth
+    /// anything while it's current.
+    fn status(&self, devices: &[DeviceStatus]) {
+        if let Err(e) = self.emitter.status(devices) {
+            warn!("Error sending device status to {}: {}", self.name, e);
+        }
+    }
+}
+
+fn what(entry: &Entry) -> &'static str {
+    match entry {
+        Entry::Reading { .. } => "event",
+        Entry::Alert { .. } => "alert",
+    }
+}
+
+/// What to do about something an emitter failed to send.
+#[derive(Debug)]
+enum Failure {
+    /// Send it again later, waiting at least this long if the service
+    /// said so
+    Retry(Option<Duration>),
+    /// Give up on it
+    Drop,
+    /// Give up on the emitter
+    Disable,
+}
+
+impl From<&EmitterError> for Failure {
+    fn from(error: &EmitterError) -> Failure {
+        match error {
+            EmitterError::Transient(_) => Failure::Retry(None),
+            EmitterError::RateLimited { retry_after } => Failure::Retry(*retry_after),
+            EmitterError::Config(_) | EmitterError::Rejected(_) => Failure::Drop,
+            EmitterError::Auth(_) => Failure::Disable,
+        }
+    }
+}
+
+/// How many spooled entries to send before checking the queue again.
+const REPLAY_BATCH: usize = 100;
+
+/// Keeps what a module's emitter failed to send in a spool, to send again,
+/// in order, backing off for as long as it keeps failing.
+#[derive(Debug)]
+struct Retry {
+    spool: Spool,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    backoff: Duration,
+    next_attempt: Instant,
+    replayed: usize,
+}
+
+impl Retry {
+    fn new(options: &RetryOptions) -> Result<Retry> {
+        Ok(Retry {
+            spool: Spool::open(options)?,
+            initial_backoff: options.initial_backoff,
+            max_backoff: options.max_backoff,
+            backoff: options.initial_backoff,
+            next_attempt: Instant::now(),
+            replayed: 0,
+        })
+    }
+
+    /// Whether new entries have to wait their turn.
+    fn waiting(&self) -> bool {
+        !self.spool.is_empty() || Instant::now() < self.next_attempt
+    }
+
+    /// How long until it's time to try again, if there's anything to try.
+    fn timeout(&self) -> Option<Duration> {
+        if self.spool.is_empty() {
+            return None;
+        }
+        Some(self.next_attempt.saturating_duration_since(Instant::now()))
+    }
+
+    /// Wait before the next attempt, returning for how long.
+    fn back_off(&mut self, retry_after: Option<Duration>) -> Duration {
+        let delay = retry_after.unwrap_or(self.backoff);
+        self.next_attempt = Instant::now() + delay;
+        self.backoff = (self.backoff * 2).min(self.max_backoff);
+        delay
+    }
+}
+
+/// Feeds a module's emitter on its worker's thread, and decides what to do
+/// when that fails.
+#[derive(Debug)]
+struct Delivery {
+    module: Module,
+    retry: Option<Retry>,
+    breaker: Breaker,
+    health: Arc<Mutex<Health>>, // A copy for the dispatcher
+}
+
+impl Delivery {
+    fn new(module: Module, health: Arc<Mutex<Health>>) -> Result<Delivery> {
+        Ok(Delivery {
+            retry: module.retry.as_ref().map(Retry::new).transpose()?,
+            breaker: Breaker::new(&module.name, module.breaker.clone()),
+            module,
+            health,
+        })
+    }
+
+    /// How long until it's time to send the spooled entries, if there are
+    /// any.
+    fn timeout(&self) -> Option<Duration> {
+        if self.breaker.state() == State::Disabled {
+            return None;
+        }
+        let timeout = self.retry.as_ref().and_then(Retry::timeout)?;
+        Some(timeout.max(self.breaker.remaining().unwrap_or_default()))
+    }
+
+    fn handle(&mut self, message: &Message) {
+        if let Message::Status(devices) = message {
+            if self.breaker.state() == State::Closed {
+                self.module.status(devices);
+            }
+            return;
+        }
+        for entry in self.module.prepare(message) {
+            self.deliver(&entry);
+        }
+        self.update_health();
+    }
+
+    /// Send an entry, or keep it to send later.
+    fn deliver(&mut self, entry: &Entry) {
+        let waiting = self.retry.as_ref().is_some_and(Retry::waiting);
+        let sent = if waiting || !self.breaker.allows() {
+            self.keep(entry)
+        } else {
+            match self.module.send(entry) {
+                Ok(outcome) => {
+                    self.succeeded();
+                    outcome == Outcome::Sent
+                }
+                Err(e) => self.failed(entry, e) && self.keep(entry),
+            }
+        };
+        // Spooled entries will be sent eventually, so they count as sent,
+        // but ones the emitter held back might never be
+        if let (true, Entry::Reading { event }, Some(changes)) = (sent, entry, &self.module.changes)
+        {
+            changes.sent(event);
+        }
+    }
+
+    fn succeeded(&mut self) {
+        self.breaker.succeeded();
+        if let Some(retry) = &mut self.retry {
+            retry.backoff = retry.initial_backoff;
+        }
+    }
+
+    /// Say why an entry couldn't be sent, and decide whether to keep it to
+    /// send later.
+    fn failed(&mut self, entry: &Entry, error: EmitterError) -> bool {
+        let name = &self.module.name;
+        match Failure::from(&error) {
+            Failure::Retry(retry_after) => {
+                // Only warn about the first failure, and the breaker says
+                // when it gives up
+                let quiet = self.breaker.state() != State::Closed
+                    || self
+                        .retry
+                        .as_ref()
+                        .is_some_and(|retry| !retry.spool.is_empty());
+                self.breaker.failed(&error.to_string());
+                let delay = self.retry.as_mut().map(|retry| retry.back_off(retry_after));
+                let message = match delay {
+                    Some(delay) => format!(
+                        "Error sending {} to {}, retrying in {}: {}",
+                        what(entry),
+                        name,
+                        humantime::format_duration(delay),
+                        error
+                    ),
+                    None => format!("Error sending {} to {}: {}", what(entry), name, error),
+                };
+                if quiet {
+                    debug!("{}", message);
+                } else {
+                    warn!("{}", message);
+                }
+                delay.is_some()
+            }
+            Failure::Drop => {
+                warn!(
+                    "Error sending {} to {}, which won't be retried: {}",
+                    what(entry),
+                    name,
+                    error
+                );
+                false
+            }
+            Failure::Disable => {
+                error!(
+                    "Error sending {} to {}, which is disabled until tilted is restarted: {}",
+                    what(entry),
+                    name,
+                    error
+                );
+                self.breaker.disable(&error.to_string());
+                // Keep it for after the restart
+                true
+            }
+        }
+    }
+
+    fn update_health(&self) {
+        let mut health = self.health.lock().unwrap();
+        *health = self.breaker.health.clone();
+        health.spooled = self.retry.as_ref().map_or(0, |retry| retry.spool.len());
+    }
+
+    /// Spool an entry to send later, returning whether it was.
+    fn keep(&mut self, entry: &Entry) -> bool {
+        let retry = match &mut self.retry {
+            Some(retry) => retry,
+            None => return false,
+        };
+        if let Err(e) = retry.spool.push(entry.clone()) {
+            warn!(
+                "Couldn't spool {} for {}: {}",
+                what(entry),
+                self.module.name,
+                e
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Send some of what's spooled, oldest first, if it's time to.
+    fn replay(&mut self) {
+        if self.timeout() != Some(Duration::from_secs(0)) || !self.breaker.allows() {
+            return;
+        }
+        for _ in 0..REPLAY_BATCH {
+            let entry = match self.retry.as_mut().and_then(|retry| retry.spool.front()) {
+                Some(entry) => entry.clone(),
+                None => break,
+            };
+            match self.module.send(&entry) {
+                Ok(_) => self.breaker.succeeded(),
+                Err(e) => {
+                    if self.failed(&entry, e) {
+                        self.update_health();
+                        return;
+                    }
+                }
+            }
+            if let Some(retry) = &mut self.retry {
+                retry.replayed += 1;
+                if let Err(e) = retry.spool.pop() {
+                    warn!("Couldn't update the spool for {}: {}", self.module.name, e);
+                }
+            }
+        }
+        if let Some(retry) = &mut self.retry {
+            if retry.spool.is_empty() {
+                info!(
+                    "Sent {} spooled events to {}, which is working again",
+                    retry.replayed, self.module.name
+                );
+                retry.replayed = 0;
+                retry.backoff = retry.initial_backoff;
+            }
+        }
+        self.update_health();
+    }
+
+    fn start(&mut self) {
+        if let Err(e) = self.module.emitter.start() {
+            if let Failure::Disable = Failure::from(&e) {
+                error!(
+                    "Couldn't start {}, which is disabled until tilted is restarted: {}",
+                    self.module.name, e
+                );
+                self.breaker.disable(&e.to_string());
+            } else {
+                warn!("Couldn't start {}: {}", self.module.name, e);
+            }
+            self.update_health();
+        }
+    }
+
+    /// Let the emitter send what it's held back, or spool it if the
+    /// emitter isn't working, and say goodbye.
+    fn close(mut self) {
+        let flushed = self.breaker.state() == State::Closed
+            && match self.module.emitter.flush() {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Error flushing {}: {}", self.module.name, e);
+                    false
+                }
+            };
+        if !flushed {
+            let dropped = self
+                .module
+                .emitter
+                .held_back()
+                .into_iter()
+                .filter(|event| {
+                    !self.keep(&Entry::Reading {
+                        event: event.clone(),
+                    })
+                })
+                .count();
+            if dropped > 0 {
+                warn!(
+                    "Dropping {} readings held back by {}, since they couldn't be spooled",
+                    dropped, self.module.name
+                );
+            }
+        }
+        self.module.emitter.shutdown();
+        let retry = match self.retry {
+            Some(retry) => retry,
+            None => return,
+        };
+        if !retry.spool.is_empty() && !retry.spool.is_persistent() {
+            warn!(
+                "Dropping {} unsent events for {}, since it has no spool file",
+                retry.spool.len(),
+                self.module.name
+            );
+        }
+    }
+}
+
+/// A thread that feeds one emitter from its own queue, so a slow emitter
+/// can't hold up the others, or the sources.
+#[derive(Debug)]
+struct Worker {
+    name: String,
+    filter: Filter,
+    queue: Arc<BoundedQueue<Message>>,
+    health: Arc<Mutex<Health>>,
+    overflowing: AtomicBool,
+    handle: JoinHandle<()>,
+}
+
+impl Worker {
+    fn spawn(module: Module) -> Result<Worker> {
+        let name = module.name.clone();
+        let filter = Filter::new(&module);
+        let queue = Arc::new(BoundedQueue::new(module.queue_size, module.overflow));
+        let health = Arc::new(Mutex::new(Health::default()));
+        let mut delivery = Delivery::new(module, health.clone())?;
+        let handle = {
+            let queue = queue.clone();
+            thread::Builder::new()
+                .name(format!("emitter {}", name))
+                .spawn(move || {
+                    delivery.start();
+                    loop {
+                        // Wake up when it's time to retry, even if nothing
+                        // new has arrived
+                        let message = match delivery.timeout() {
+                            Some(timeout) => match queue.pop_timeout(timeout) {
+                                Popped::Item(message) => Some(message),
+                                Popped::TimedOut => None,
+                                Popped::Closed => break,
+                            },
+                            None => match queue.pop() {
+                                Some(message) => Some(message),
+                                None => break,
+                            },
+                        };
+                        if let Some(message) = message {
+                            delivery.handle(&message);
+                        }
+                        delivery.replay();
+                    }
+                    delivery.close();
+                })?
+        };
+        Ok(Worker {
+            name,
+            filter,
+            queue,
+            health,
+            overflowing: AtomicBool::new(false),
+            handle,
+        })
+    }
+
+    fn send(&self, message: Message) {
+        let message = match self.filter.select(message) {
+            Some(message) => message,
+            None => return,
+        };
+        let pushed = self.queue.push(message);
+        let overflowing = pushed != Pushed::Queued;
+        if overflowing == self.overflowing.swap(overflowing, Ordering::Relaxed) {
+            return;
+        }
+        // Only log when it starts and stops, not for every event
+        match pushed {
+            Pushed::Queued => info!("The queue for {} has room again", self.name),
+            Pushed::DroppedOldest => warn!(
+                "The queue for {} is full, dropping the oldest events",
+                self.name
+            ),
+            Pushed::DroppedNewest => warn!(
+                "The queue for {} is full, dropping the newest events",
+                self.name
+            ),
+        }
+    }
+}
+
+pub struct Dispatcher {
+    workers: Vec<Worker>,
+}
+
+impl Dispatcher {
+    pub fn new(modules: Vec<Module>) -> Result<Dispatcher> {
+        let workers = modules
+            .into_iter()
+            .map(Worker::spawn)
+            .collect::<Result<_>>()?;
+        Ok(Dispatcher { workers })
+    }
+
+    pub fn dispatch(&self, event: &Event) {
+        self.send(Message::Reading(Arc::new(event.clone())));
+        for alert in &event.alerts {
+            self.alert(alert);
+        }
+    }
+
+    pub fn alert(&self, alert: &Alert) {
+        self.send(Message::Alert(Arc::new(alert.clone())));
+    }
+
+    pub fn status(&self, devices: &[DeviceStatus]) {
+        self.send(Message::Status(Arc::new(devices.to_vec())));
+    }
+
+    /// How every emitter is doing.
+    pub fn health(&self) -> BTreeMap<String, Health> {
+        self.workers
+            .iter()
+            .map(|worker| {
+                let mut health = worker.health.lock().unwrap().clone();
+                health.queued = worker.queue.len();
+                (worker.name.clone(), health)
+            })
+            .collect()
+    }
+
+    fn send(&self, message: Message) {
+        for worker in &self.workers {
+            worker.send(message.clone());
+        }
+    }
+
+    /// Let the workers finish what's queued and flush their emitters, and
+    /// wait up to `timeout` for them.
+    pub fn close(self, timeout: Duration) {
+        for worker in &self.workers {
+            worker.queue.close();
+        }
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline
+            && !self
+                .workers
+                .iter()
+                .all(|worker| worker.handle.is_finished())
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+        for worker in self.workers {
+            if !worker.handle.is_finished() {
+                // It's stuck, and will be stopped along with the process
+                warn!(
+                    "Gave up waiting for {} to send {} queued events",
+                    worker.name,
+                    worker.queue.len()
+                );
+            } else if worker.handle.join().is_err() {
+                warn!("The worker for {} panicked", worker.name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::batch::Batch;
+
+    #[test]
+    fn filters() {
+        let module: crate::emitters::EmitterSection = toml::from_str(
+            r#"emitter = "log"
+include = { colors = ["red"], macs = ["aa:bb:cc:dd:ee:ff"], batches = ["IPA #42"] }
+exclude = { names = ["Spare"] }
+"#,
+        )
+        .unwrap();
+        let module = crate::emitters::init(
+            &vec![("log".to_string(), module)].into_iter().collect(),
+            &Default::default(),
+        )
+        .unwrap()
+        .pop()
+        .unwrap();
+        let event = |color, mac: Option<&str>, name: Option<&str>, batch: Option<&str>| {
+            let mut event = Event::new(color, mac.map(String::from), Utc::now(), 68., 1.05);
+            event.name = name.map(String::from);
+            event.batch = batch.map(|batch| Batch {
+                name: batch.to_string(),
+                style: None,
+                start: None,
+                end: None,
+                og: None,
+                target_fg: None,
+            });
+            event
+        };
+        let filter = Filter::new(&module);
+        assert!(filter.wants(&event(Color::Red, None, None, None)));
+        assert!(!filter.wants(&event(Color::Blue, None, None, None)));
+        assert!(filter.wants(&event(Color::Blue, Some("AA:BB:CC:DD:EE:FF"), None, None)));
+        assert!(filter.wants(&event(Color::Blue, None, None, Some("IPA #42"))));
+        assert!(!filter.wants(&event(Color::Red, None, Some("Spare"), None)));
+    }
+
+    #[test]
+    fn select() -> Result<()> {
+        let module: crate::emitters::EmitterSection = toml::from_str(
+            r#"emitter = "log"
+events = "readings"
+include = { colors = ["red"] }
+"#,
+        )?;
+        let module = crate::emitters::init(
+            &vec![("log".to_string(), module)].into_iter().collect(),
+            &Default::default(),
+        )?
+        .pop()
+        .unwrap();
+        let filter = Filter::new(&module);
+        let red = Event::new(Color::Red, None, Utc::now(), 68., 1.05);
+        let blue = Event::new(Color::Blue, None, Utc::now(), 68., 1.05);
+        assert!(filter
+            .select(Message::Reading(Arc::new(red.clone())))
+            .is_some());
+        assert!(filter
+            .select(Message::Reading(Arc::new(blue.clone())))
+            .is_none());
+        let alert = Alert {
+            kind: crate::alert::AlertKind::Threshold,
+            message: "Too warm".to_string(),
+            rule: None,
+            event: red.clone(),
+        };
+        assert!(filter.select(Message::Alert(Arc::new(alert))).is_none());
+        let status = |event: &Event| DeviceStatus {
+            last: event.clone(),
+            last_seen: event.timestamp,
+        };
+        match filter.select(Message::Status(Arc::new(vec![status(&red), status(&blue)]))) {
+            Some(Message::Status(devices)) => {
+                assert_eq!(devices.len(), 1);
+                assert_eq!(devices[0].last.color, Color::Red);
+            }
+            message => panic!("unexpected {:?}", message),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pipeline() -> Result<()> {
+        let module: crate::emitters::EmitterSection = toml::from_str(
+            r#"emitter = "log"
+pipeline = [
+    { stage = "validate" },
+    { stage = "rename", devices = { colors = ["red"] }, name = "Fermenter" },
+]
+"#,
+        )?;
+        let mut module = crate::emitters::init(
+            &vec![("log".to_string(), module)].into_iter().collect(),
+            &Default::default(),
+        )?
+        .pop()
+        .unwrap();
+        let mut prepare = |color, gravity| {
+            let event = Event::new(color, None, Utc::now(), 68., gravity);
+            module
+                .prepare(&Message::Reading(Arc::new(event)))
+                .into_iter()
+                .map(|entry| entry.event().name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            prepare(Color::Red, 1.05),
+            vec![Some("Fermenter".to_string())]
+        );
+        assert_eq!(prepare(Color::Blue, 1.05), vec![None]);
+        assert_eq!(prepare(Color::Red, 2.), vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn expressions() -> Result<()> {
+        let module: crate::emitters::EmitterSection = toml::from_str(
+            r#"emitter = "log"
+when = "points < 20 && color == 'red'"
+fields = { points = "round((gravity - 1) * 1000)" }
+"#,
+        )?;
+        let mut module = crate::emitters::init(
+            &vec![("log".to_string(), module)].into_iter().collect(),
+            &Default::default(),
+        )?
+        .pop()
+        .unwrap();
+        let mut prepare = |color, gravity| {
+            let event = Event::new(color, None, Utc::now(), 68., gravity);
+            module
+                .prepare(&Message::Reading(Arc::new(event)))
+                .into_iter()
+                .map(|entry| entry.event().variables().unwrap()["points"].clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(prepare(Color::Red, 1.018), vec![serde_json::json!(18.)]);
+        assert!(prepare(Color::Red, 1.05).is_empty());
+        assert!(prepare(Color::Blue, 1.018).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn convert() -> Result<()> {
+        let (url, requests) = crate::emitters::test_server::serve(vec![]);
+        let module: crate::emitters::EmitterSection = toml::from_str(&format!(
+            r#"emitter = "http"
+url = "{}"
+temperature-unit = "fahrenheit"
+pipeline = [{{ stage = "convert", temperature-unit = "celsius", gravity-unit = "plato" }}]
+when = "gravity < 1.06"
+payload = {{ gravity = "{{gravity}}", temperature = "{{temperature}}" }}
+"#,
+            url
+        ))?;
+        let dispatcher = Dispatcher::new(crate::emitters::init(
+            &vec![("http".to_string(), module)].into_iter().collect(),
+            &Default::default(),
+        )?)?;
+        dispatcher.dispatch(&Event::new(Color::Red, None, Utc::now(), 68., 1.050));
+        dispatcher.close(Duration::from_secs(5));
+        let request = requests.try_recv()?;
+        let body: HashMap<String, String> = serde_json::from_str(&request.body)?;
+        // `when` sees the gravity in SG, and the payload has it in Plato
+        assert_eq!(body["temperature"], "20");
+        assert_eq!(body["gravity"], "12.39");
+        Ok(())
+    }
+
+    #[test]
+    fn smoothed_metrics() -> Result<()> {
+        let module: crate::emitters::EmitterSection =
+            toml::from_str("emitter = \"log\"\nsmoothing = \"average\"\n")?;
+        let sections = crate::processors::Sections {
+            smoothing: toml::from_str("[average]\nfilter = \"moving-average\"\nwindow = 2\n")?,
+            ..Default::default()
+        };
+        let module = crate::emitters::init(
+            &vec![("log".to_string(), module)].into_iter().collect(),
+            &sections,
+        )?
+        .pop()
+        .unwrap();
+        let mut event = Event::new(Color::Red, None, Utc::now(), 68., 1.02);
+        event.og = Some(1.05);
+        metrics::og_metrics(&mut event);
+        event.smoothed.insert(
+            "average".to_string(),
+            Reading {
+                temperature: 68.,
+                gravity: 1.03,
+            },
+        );
+        let event = module.view(event);
+        assert_eq!(event.abv, Some(2.63));
+        assert_eq!(event.attenuation, Some(40.));
+        Ok(())
+    }
+
+    #[test]
+    fn deadband() -> Result<()> {
+        let deadband: Deadband = toml::from_str(
+            r#"gravity = 0.002
+temperature = 1
+temperature-unit = "celsius"
+"#,
+        )?;
+        let changes = ChangeDetection::new(Some(deadband), Some(Duration::from_secs(60 * 60)))?;
+        let start = Utc::now();
+        let readings = [
+            (0, 68., 1.050, true),
+            (1, 68., 1.050, false),
+            (2, 69., 1.049, false),
+            // Off by 0.002 from the last reading that was sent
+            (3, 68., 1.048, true),
+            // Off by 1.5°F, which is less than 1°C
+            (4, 69.5, 1.048, false),
+            (5, 70., 1.048, true),
+            // The heartbeat
+            (64, 70., 1.048, false),
+            (65, 70., 1.048, true),
+        ];
+        for (at, temperature, gravity, changed) in &readings {
+            let timestamp = start + chrono::Duration::minutes(*at);
+            let event = Event::new(Color::Red, None, timestamp, *temperature, *gravity);
+            assert_eq!(changes.changed(&event), *changed, "at {}", at);
+            if *changed {
+                changes.sent(&event);
+            }
+        }
+        // Other devices have their own state
+        let event = Event::new(Color::Blue, None, start, 70.2, 1.048);
+        assert!(changes.changed(&event));
+        Ok(())
+    }
+
+    #[test]
+    fn deadband_min_interval() -> Result<()> {
+        let (url, requests) = crate::emitters::test_server::serve(vec![]);
+        let module: crate::emitters::EmitterSection = toml::from_str(&format!(
+            r#"emitter = "http"
+url = "{}"
+min-interval = "10m"
+payload = {{ gravity = "{{gravity}}" }}
+deadband = {{ gravity = 0.002 }}
+"#,
+            url
+        ))?;
+        let modules = crate::emitters::init(
+            &vec![("http".to_string(), module)].into_iter().collect(),
+            &Default::default(),
+        )?;
+        let dispatcher = Dispatcher::new(modules)?;
+        let start = Utc::now() - chrono::Duration::hours(1);
+        for &(at, gravity) in &[(0, 1.050), (5, 1.047), (11, 1.047)] {
+            let timestamp = start + chrono::Duration::minutes(at);
+            dispatcher.dispatch(&Event::new(Color::Red, None, timestamp, 68., gravity));
+        }
+        dispatcher.close(Duration::from_secs(5));
+        let received = requests
+            .try_iter()
+            .map(|request| {
+                let body: HashMap<String, String> = serde_json::from_str(&request.body).unwrap();
+                body["gravity"].clone()
+            })
+            .collect::<Vec<_>>();
+        // The second reading is held back by min-interval, so the third is
+        // still compared to the first
+        assert_eq!(received, vec!["1.05", "1.047"]);
+        Ok(())
+    }
+
+    #[test]
+    fn spool_held_back() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let spool = directory.path().join("spool.jsonl");
+        let spawn = |url: &str, failures| -> Result<Dispatcher> {
+            let module: crate::emitters::EmitterSection = toml::from_str(&format!(
+                r#"emitter = "http"
+url = "{}"
+min-interval = "10m"
+aggregate = "last"
+payload = {{ gravity = "{{gravity}}" }}
+retry = {{ initial-backoff = "1h", spool = "{}" }}
+circuit-breaker = {{ failures = {} }}
+"#,
+                url,
+                spool.display(),
+                failures
+            ))?;
+            Dispatcher::new(crate::emitters::init(
+                &vec![("http".to_string(), module)].into_iter().collect(),
+                &Default::default(),
+            )?)
+        };
+        let spooled = || -> Result<Vec<f64>> {
+            std::fs::read_to_string(&spool)?
+                .lines()
+                .map(|line| Ok(serde_json::from_str::<Entry>(line)?.event().gravity))
+                .collect()
+        };
+        let start = Utc::now() - chrono::Duration::hours(1);
+        let minutes = |minutes| start + chrono::Duration::minutes(minutes);
+
+        // The held back reading is spooled when flushing it fails
+        let (url, requests) = crate::emitters::test_server::serve(vec![200, 503]);
+        let dispatcher = spawn(&url, 5)?;
+        dispatcher.dispatch(&Event::new(Color::Red, None, minutes(0), 68., 1.050));
+        dispatcher.dispatch(&Event::new(Color::Red, None, minutes(1), 68., 1.049));
+        dispatcher.close(Duration::from_secs(5));
+        assert_eq!(requests.try_iter().count(), 2);
+        assert_eq!(spooled()?, vec![1.049]);
+        std::fs::remove_file(&spool)?;
+
+        // Or straight away, without trying, when the breaker is open
+        let (url, requests) = crate::emitters::test_server::serve(vec![200, 503]);
+        let dispatcher = spawn(&url, 1)?;
+        dispatcher.dispatch(&Event::new(Color::Red, None, minutes(0), 68., 1.050));
+        dispatcher.dispatch(&Event::new(Color::Red, None, minutes(1), 68., 1.049));
+        dispatcher.dispatch(&Event::new(Color::Blue, None, minutes(2), 68., 1.060));
+        dispatcher.close(Duration::from_secs(5));
+        assert_eq!(requests.try_iter().count(), 2);
+        assert_eq!(spooled()?, vec![1.060, 1.049]);
+        Ok(())
+    }
+
+    #[test]
+    fn retry() -> Result<()> {
+        let (url, requests) = crate::emitters::test_server::serve(vec![503, 503, 400]);
+        let directory = tempfile::tempdir()?;
+        let spool = directory.path().join("spool.jsonl");
+        let module: crate::emitters::EmitterSection = toml::from_str(&format!(
+            r#"emitter = "http"
+url = "{}"
+min-interval = "0s"
+payload = {{ gravity = "{{gravity}}" }}
+retry = {{ initial-backoff = "10ms", spool = "{}" }}
+"#,
+            url,
+            spool.display()
+        ))?;
+        let modules = crate::emitters::init(
+            &vec![("http".to_string(), module)].into_iter().collect(),
+            &Default::default(),
+        )?;
+        let dispatcher = Dispatcher::new(modules)?;
+        let start = Utc::now() - chrono::Duration::hours(1);
+        for &(at, gravity) in &[(0, 1.050), (1, 1.049), (2, 1.048)] {
+            let timestamp = start + chrono::Duration::minutes(at);
+            dispatcher.dispatch(&Event::new(Color::Red, None, timestamp, 68., gravity));
+        }
+        let received = (0..5)
+            .map(|_| {
+                let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+                let body: HashMap<String, String> = serde_json::from_str(&request.body).unwrap();
+                body["gravity"].clone()
+            })
+            .collect::<Vec<_>>();
+        // The first reading fails twice, and the rest wait their turn until
+        // the service rejects it for good
+        assert_eq!(received, vec!["1.05", "1.05", "1.05", "1.049", "1.048"]);
+        dispatcher.close(Duration::from_secs(5));
+        assert_eq!(std::fs::read_to_string(&spool)?, "");
+        Ok(())
+    }
+}
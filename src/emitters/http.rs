@@ -1,17 +1,20 @@
-use super::{Emitter, EmitterConfig};
-use crate::event::Event;
-use anyhow::Result;
-use serde::Deserialize;
+use super::{Emitter, EmitterConfig, EmitterError, Outcome};
+use crate::alert::Alert;
+use crate::event::{Event, Reading, TimestampFormat};
+use crate::processors::median;
+use crate::units::{round, GravityUnit, TemperatureUnit};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-    time::{Duration, SystemTime},
+    collections::{HashMap, VecDeque},
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
 };
-use thiserror::Error;
 use tinytemplate::TinyTemplate;
-
-#[derive(Error, Debug)]
-pub enum HttpError {}
+use tracing::{debug, warn};
 
 #[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "lowercase")]
@@ -21,15 +24,62 @@ enum Formats {
     Form,
 }
 
+/// How to combine the readings from a device during `min-interval`.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Aggregate {
+    Mean,
+    Median,
+    Min,
+    Max,
+    Last,
+}
+
+impl Aggregate {
+    fn apply(self, values: &[f64]) -> f64 {
+        match self {
+            Aggregate::Mean => values.iter().sum::<f64>() / values.len() as f64,
+            Aggregate::Median => median(values),
+            Aggregate::Min => values.iter().cloned().fold(f64::INFINITY, f64::min),
+            Aggregate::Max => values.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+            Aggregate::Last => values[values.len() - 1],
+        }
+    }
+}
+
+/// The most readings to hold back from a device, so a service that's
+/// down for a long time doesn't use up all the memory. The oldest are
+/// dropped first.
+const MAX_PENDING: usize = 10_000;
+
+/// The readings from a device since its readings were last sent, to
+/// aggregate.
+#[derive(Debug)]
+struct Pending {
+    last: Event,
+    readings: VecDeque<Reading>,
+    /// Whether sending `last` failed, in which case the dispatcher has it
+    /// and it isn't held back.
+    failed: bool,
+}
+
 #[derive(Debug)]
 pub struct Http {
+    agent: ureq::Agent,
     method: String,
     uri: String,
     content_type: String,
-    payload: HashMap<String, String>,
-    last_emit: Arc<Mutex<SystemTime>>,
-    min_interval: Duration,
+    payload: Option<HashMap<String, String>>,
+    alert_payload: Option<HashMap<String, String>>,
+    last_emit: Mutex<HashMap<String, DateTime<Utc>>>, // By device
+    state_file: Option<PathBuf>,
+    aggregate: Option<Aggregate>,
+    pending: Mutex<HashMap<String, Pending>>, // By device
+    min_interval: chrono::Duration,
     format: Formats,
+    timestamp_format: TimestampFormat,
+    temperature_unit: TemperatureUnit,
+    gravity_unit: GravityUnit,
 }
 
 #[derive(Deserialize, Debug)]
@@ -47,7 +97,24 @@ pub struct HttpOptions {
     #[serde(default = "default_repeat")]
     #[serde(rename = "min-interval")]
     min_interval: Duration,
-    payload: HashMap<String, String>,
+    #[serde(rename = "timestamp-format")]
+    #[serde(default)]
+    timestamp_format: TimestampFormat,
+    #[serde(rename = "temperature-unit")]
+    #[serde(default)]
+    temperature_unit: TemperatureUnit,
+    #[serde(rename = "gravity-unit")]
+    #[serde(default)]
+    gravity_unit: GravityUnit,
+    payload: Option<HashMap<String, String>>,
+    #[serde(rename = "alert-payload")]
+    alert_payload: Option<HashMap<String, String>>,
+    #[serde(rename = "state-file")]
+    state_file: Option<PathBuf>,
+    aggregate: Option<Aggregate>,
+    #[serde(with = "humantime_serde")]
+    #[serde(default = "default_timeout")]
+    timeout: Duration,
 }
 
 fn default_content_type() -> String {
@@ -59,43 +126,170 @@ fn default_format() -> Formats {
 fn default_repeat() -> Duration {
     Duration::from_secs(5 * 60)
 }
+fn default_timeout() -> Duration {
+    Duration::from_secs(30)
+}
 fn default_method() -> String {
     "POST".to_string()
 }
 
 impl EmitterConfig for HttpOptions {
     fn get_emitter(&self) -> Result<Box<dyn Emitter>> {
+        if self.payload.is_none() && self.alert_payload.is_none() {
+            bail!("The http emitter needs a payload, an alert-payload, or both");
+        }
+        // Broken templates would fail for every event, so find them now
+        for payload in self.payload.iter().chain(&self.alert_payload) {
+            for (key, value) in payload {
+                for template in &[key, value] {
+                    TinyTemplate::new()
+                        .add_template("template", template)
+                        .with_context(|| format!("Invalid template {:?}", template))?;
+                }
+            }
+        }
         Ok(Box::new(Http {
+            agent: ureq::AgentBuilder::new().timeout(self.timeout).build(),
             method: self.method.clone(),
             uri: self.url.clone(),
             content_type: self.content_type.clone(),
             format: self.format.clone(),
             payload: self.payload.clone(),
-            min_interval: self.min_interval,
-            last_emit: Arc::new(Mutex::new(SystemTime::UNIX_EPOCH)),
+            alert_payload: self.alert_payload.clone(),
+            min_interval: chrono::Duration::from_std(self.min_interval)?,
+            timestamp_format: self.timestamp_format.clone(),
+            temperature_unit: self.temperature_unit,
+            gravity_unit: self.gravity_unit,
+            last_emit: Mutex::new(match &self.state_file {
+                Some(state_file) => load_state(state_file),
+                None => HashMap::new(),
+            }),
+            state_file: self.state_file.clone(),
+            aggregate: self.aggregate,
+            pending: Mutex::new(HashMap::new()),
         }))
     }
 }
 
-impl Emitter for Http {
-    fn emit(&self, event: &Event) -> Result<()> {
+/// When readings from each device were last sent, from a previous run.
+fn load_state(state_file: &Path) -> HashMap<String, DateTime<Utc>> {
+    let state = match std::fs::read_to_string(state_file) {
+        Ok(state) => state,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return HashMap::new(),
+        Err(e) => {
+            warn!("Couldn't read {}: {}", state_file.display(), e);
+            return HashMap::new();
+        }
+    };
+    serde_json::from_str(&state).unwrap_or_else(|e| {
+        warn!("Ignoring invalid state in {}: {}", state_file.display(), e);
+        HashMap::new()
+    })
+}
+
+/// Write the state to a temporary file first, so a crash can't leave it
+/// half written.
+fn save_state(state_file: &Path, state: &HashMap<String, DateTime<Utc>>) -> Result<()> {
+    let temporary = state_file.with_extension("tmp");
+    std::fs::write(&temporary, serde_json::to_string(state)?)?;
+    std::fs::rename(&temporary, state_file)?;
+    Ok(())
+}
+
+impl Http {
+    /// The variables available to the payload templates.
+    fn context(&self, event: &Event) -> Result<serde_json::Value> {
+        let mut context = event.variables()?;
+        context["timestamp"] = self.timestamp_format.format(&event.timestamp).into();
+
+        // The units of a convert stage win over the emitter's own
+        let temperature_unit = event.temperature_unit.unwrap_or(self.temperature_unit);
+        let gravity_unit = event.gravity_unit.unwrap_or(self.gravity_unit);
+        let temperature = event.temperature;
+        let gravity = event.gravity;
+        if temperature_unit != TemperatureUnit::Fahrenheit {
+            context["temperature"] = round(temperature_unit.convert(temperature), 2).into();
+        }
+        if gravity_unit != GravityUnit::Sg {
+            let uncorrected_gravity = event.uncorrected_gravity.unwrap_or(gravity);
+            context["gravity"] = round(gravity_unit.convert(gravity), 2).into();
+            context["uncorrected_gravity"] =
+                round(gravity_unit.convert(uncorrected_gravity), 2).into();
+            if let Some(og) = event.og {
+                context["og"] = round(gravity_unit.convert(og), 2).into();
+            }
+        }
+        Ok(context)
+    }
+
+    /// The context for the aggregate of the readings from the event's
+    /// device since the last time they were sent.
+    fn aggregate(&self, event: &Event, aggregate: Aggregate) -> Result<serde_json::Value> {
+        let pending = self.pending.lock().unwrap();
+        let readings = &pending[&event.device()].readings;
+        let temperatures = readings.iter().map(|r| r.temperature).collect::<Vec<_>>();
+        let gravities = readings.iter().map(|r| r.gravity).collect::<Vec<_>>();
+        let mut event = event.clone();
+        event.temperature = round(aggregate.apply(&temperatures), 2);
+        event.gravity = round(aggregate.apply(&gravities), 4);
+        let mut context = self.context(&event)?;
+        context["samples"] = readings.len().into();
+        context["aggregate"] = serde_json::to_value(aggregate)?;
+        Ok(context)
+    }
+
+    /// Send a reading, or the aggregate of the readings from its device,
+    /// and remember when.
+    fn deliver(
+        &self,
+        payload: &HashMap<String, String>,
+        event: &Event,
+    ) -> Result<(), EmitterError> {
+        let mut context = match self.aggregate {
+            Some(aggregate) => self.aggregate(event, aggregate)?,
+            None => self.context(event)?,
+        };
+        if self.aggregate.is_none() {
+            context["samples"] = 1.into();
+        }
+        self.send(payload, &context)?;
+
+        let device = event.device();
+        self.pending.lock().unwrap().remove(&device);
+        let mut last_emit = self.last_emit.lock().unwrap();
+        // A retried reading can be older than the last one sent
+        if last_emit
+            .get(&device)
+            .is_some_and(|last| *last >= event.timestamp)
         {
-            let mut last_emit = self.last_emit.lock().unwrap();
-            if *last_emit + self.min_interval > SystemTime::now() {
-                return Ok(());
+            return Ok(());
+        }
+        last_emit.insert(device, event.timestamp);
+        if let Some(state_file) = &self.state_file {
+            if let Err(e) = save_state(state_file, &last_emit) {
+                warn!("Couldn't save state to {}: {}", state_file.display(), e);
             }
-            *last_emit = SystemTime::now();
         }
-        let mut request =
-            ureq::request(&self.method, &self.uri).set("Content-type", &self.content_type);
-        let payload = self
-            .payload
+        Ok(())
+    }
+
+    /// Render the payload templates and send them to the service.
+    fn send(
+        &self,
+        payload: &HashMap<String, String>,
+        context: &serde_json::Value,
+    ) -> Result<(), EmitterError> {
+        let mut request = self
+            .agent
+            .request(&self.method, &self.uri)
+            .set("Content-type", &self.content_type);
+        let payload = payload
             .iter()
             .map(|(key, value)| {
                 let mut tt = TinyTemplate::new();
                 tt.add_template("key", key)?;
                 tt.add_template("value", value)?;
-                Ok((tt.render("key", event)?, tt.render("value", event)?))
+                Ok((tt.render("key", context)?, tt.render("value", context)?))
             })
             .collect::<Result<HashMap<_, _>>>()?;
         let request = match self.format {
@@ -118,3 +312,310 @@ impl Emitter for Http {
         Ok(())
     }
 }
+
+impl Emitter for Http {
+    fn emit(&self, event: &Event) -> Result<Outcome, EmitterError> {
+        let payload = match &self.payload {
+            Some(payload) => payload,
+            None => return Ok(Outcome::Skipped),
+        };
+        // Rate limit by when the readings were taken, so every device
+        // gets its turn
+        let device = event.device();
+        if self.aggregate.is_some() {
+            let last_emit = self.last_emit.lock().unwrap().get(&device).copied();
+            let mut pending = self.pending.lock().unwrap();
+            let newest = pending
+                .get(&device)
+                .map(|pending| pending.last.timestamp)
+                .max(last_emit);
+            if newest.is_none_or(|newest| event.timestamp > newest) {
+                let pending = pending.entry(device.clone()).or_insert_with(|| Pending {
+                    last: event.clone(),
+                    readings: VecDeque::new(),
+                    failed: false,
+                });
+                pending.last = event.clone();
+                pending.failed = false;
+                if pending.readings.len() == MAX_PENDING {
+                    pending.readings.pop_front();
+                }
+                pending.readings.push_back(Reading {
+                    temperature: event.temperature,
+                    gravity: event.gravity,
+                });
+            } else if let Some(pending) = pending.get_mut(&device) {
+                // A retried reading is already in the aggregate, or was sent
+                // with an earlier one. If it's the one that failed, it's held
+                // back again from here on.
+                if pending.last.timestamp == event.timestamp {
+                    pending.failed = false;
+                }
+            }
+        }
+        if let Some(last_emit) = self.last_emit.lock().unwrap().get(&device) {
+            let next = *last_emit + self.min_interval;
+            if event.timestamp < next {
+                debug!(
+                    "Not sending the reading from {} to {} until {}",
+                    device, self.uri, next
+                );
+                return Ok(Outcome::Skipped);
+            }
+        }
+        if let Err(e) = self.deliver(payload, event) {
+            if let Some(pending) = self.pending.lock().unwrap().get_mut(&device) {
+                pending.failed = true;
+            }
+            return Err(e);
+        }
+        Ok(Outcome::Sent)
+    }
+
+    fn flush(&self) -> Result<(), EmitterError> {
+        let payload = match &self.payload {
+            Some(payload) => payload,
+            None => return Ok(()),
+        };
+        // Readings held back by min-interval would be lost otherwise
+        for event in &self.held_back() {
+            self.deliver(payload, event)?;
+        }
+        Ok(())
+    }
+
+    fn held_back(&self) -> Vec<Event> {
+        self.pending
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|pending| !pending.failed)
+            .map(|pending| pending.last.clone())
+            .collect()
+    }
+
+    fn alert(&self, alert: &Alert) -> Result<(), EmitterError> {
+        // Alerts are rare and important, so they aren't rate limited
+        let payload = match &self.alert_payload {
+            Some(payload) => payload,
+            None => return Ok(()),
+        };
+        let mut context = self.context(&alert.event)?;
+        context["alert"] = serde_json::to_value(alert)?;
+        self.send(payload, &context)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emitters::test_server::serve;
+    use crate::event::Color;
+    use std::sync::mpsc::Receiver;
+
+    fn http(url: &str, state_file: &Path) -> Box<dyn Emitter> {
+        let options: HttpOptions = toml::from_str(&format!(
+            r#"url = "{}"
+min-interval = "15m"
+state-file = "{}"
+payload = {{ color = "{{color}}", gravity = "{{gravity}}" }}
+"#,
+            url,
+            state_file.display()
+        ))
+        .unwrap();
+        options.get_emitter().unwrap()
+    }
+
+    fn received(requests: &Receiver<crate::emitters::test_server::Request>) -> Vec<String> {
+        requests
+            .try_iter()
+            .map(|request| {
+                let body: HashMap<String, String> = serde_json::from_str(&request.body).unwrap();
+                format!("{} {}", body["color"], body["gravity"])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rate_limit_per_device() -> Result<()> {
+        let (url, requests) = serve(vec![]);
+        let directory = tempfile::tempdir()?;
+        let state_file = directory.path().join("state.json");
+        let http = http(&url, &state_file);
+        let start = Utc::now();
+        let minutes = |minutes| start + chrono::Duration::minutes(minutes);
+        let readings = [
+            (Color::Red, 0, 1.050),
+            (Color::Red, 5, 1.049),
+            (Color::Blue, 6, 1.060),
+            (Color::Red, 15, 1.048),
+            (Color::Blue, 16, 1.059),
+            (Color::Blue, 21, 1.058),
+        ];
+        for (color, at, gravity) in &readings {
+            http.emit(&Event::new(*color, None, minutes(*at), 68., *gravity))?;
+        }
+        assert_eq!(
+            received(&requests),
+            vec!["red 1.05", "blue 1.06", "red 1.048", "blue 1.058"]
+        );
+
+        // A restarted emitter remembers when it last sent
+        let http = self::http(&url, &state_file);
+        http.emit(&Event::new(Color::Red, None, minutes(20), 68., 1.047))?;
+        http.emit(&Event::new(Color::Blue, None, minutes(36), 68., 1.057))?;
+        assert_eq!(received(&requests), vec!["blue 1.057"]);
+        Ok(())
+    }
+
+    #[test]
+    fn aggregate() -> Result<()> {
+        let (url, requests) = serve(vec![200, 503]);
+        let options: HttpOptions = toml::from_str(&format!(
+            r#"url = "{}"
+min-interval = "10m"
+aggregate = "mean"
+payload = {{ gravity = "{{gravity}}", temperature = "{{temperature}}", samples = "{{samples}}" }}
+"#,
+            url
+        ))?;
+        let http = options.get_emitter()?;
+        let start = Utc::now();
+        let readings = [
+            (0, 66., 1.050),
+            (3, 68., 1.049),
+            (6, 69., 1.048),
+            // The service is down, so these are sent along with the next
+            (10, 70., 1.047),
+            (20, 70., 1.046),
+            (25, 71., 1.045),
+        ];
+        for (at, temperature, gravity) in &readings {
+            let timestamp = start + chrono::Duration::minutes(*at);
+            let result = http.emit(&Event::new(
+                Color::Red,
+                None,
+                timestamp,
+                *temperature,
+                *gravity,
+            ));
+            assert_eq!(result.is_ok(), *at != 10);
+        }
+        // The last one is sent when the emitter is flushed
+        http.flush()?;
+        let received = requests
+            .try_iter()
+            .map(|request| serde_json::from_str(&request.body).unwrap())
+            .collect::<Vec<HashMap<String, String>>>();
+        let expected = [
+            ("1.05", "66", "1"),
+            ("1.048", "69", "3"),
+            ("1.0475", "69.25", "4"),
+            ("1.045", "71", "1"),
+        ];
+        assert_eq!(received.len(), expected.len());
+        for (body, (gravity, temperature, samples)) in received.iter().zip(&expected) {
+            assert_eq!(body["gravity"], *gravity);
+            assert_eq!(body["temperature"], *temperature);
+            assert_eq!(body["samples"], *samples);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pending_limit() -> Result<()> {
+        let (url, requests) = serve(vec![]);
+        let options: HttpOptions = toml::from_str(&format!(
+            r#"url = "{}"
+min-interval = "10m"
+aggregate = "max"
+payload = {{ gravity = "{{gravity}}", samples = "{{samples}}" }}
+"#,
+            url
+        ))?;
+        let http = options.get_emitter()?;
+        let start = Utc::now();
+        http.emit(&Event::new(Color::Red, None, start, 68., 1.050))?;
+        // The oldest held back readings are dropped to make room
+        for i in 1..=MAX_PENDING + 5 {
+            let timestamp = start + chrono::Duration::milliseconds(i as i64);
+            let gravity = if i <= 5 { 1.060 } else { 1.040 };
+            http.emit(&Event::new(Color::Red, None, timestamp, 68., gravity))?;
+        }
+        http.flush()?;
+        let received = requests
+            .try_iter()
+            .map(|request| serde_json::from_str(&request.body).unwrap())
+            .collect::<Vec<HashMap<String, String>>>();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1]["gravity"], "1.04");
+        assert_eq!(received[1]["samples"], MAX_PENDING.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn retried_readings_keep_the_rate_limit() -> Result<()> {
+        let (url, _requests) = serve(vec![200, 503]);
+        let directory = tempfile::tempdir()?;
+        let state_file = directory.path().join("state.json");
+        let options = format!(
+            r#"url = "{}"
+min-interval = "10m"
+aggregate = "last"
+state-file = "{}"
+payload = {{ gravity = "{{gravity}}" }}
+"#,
+            url,
+            state_file.display()
+        );
+        let http = toml::from_str::<HttpOptions>(&options)?.get_emitter()?;
+        let start = Utc::now();
+        let event = |at, gravity| {
+            let timestamp = start + chrono::Duration::minutes(at);
+            Event::new(Color::Red, None, timestamp, 68., gravity)
+        };
+        http.emit(&event(0, 1.050))?;
+        assert!(http.emit(&event(11, 1.049)).is_err());
+        http.emit(&event(22, 1.048))?;
+        // The failed reading is retried after a newer one was sent
+        http.emit(&event(11, 1.049))?;
+        http.flush()?;
+
+        let http = toml::from_str::<HttpOptions>(&options)?.get_emitter()?;
+        assert_eq!(http.emit(&event(25, 1.047))?, Outcome::Skipped);
+        Ok(())
+    }
+
+    #[test]
+    fn retried_aggregate() -> Result<()> {
+        let (url, requests) = serve(vec![200, 503, 200]);
+        let options: HttpOptions = toml::from_str(&format!(
+            r#"url = "{}"
+min-interval = "10m"
+aggregate = "mean"
+payload = {{ gravity = "{{gravity}}", samples = "{{samples}}" }}
+"#,
+            url
+        ))?;
+        let http = options.get_emitter()?;
+        let start = Utc::now();
+        let event = |at, gravity| {
+            let timestamp = start + chrono::Duration::minutes(at);
+            Event::new(Color::Red, None, timestamp, 68., gravity)
+        };
+        http.emit(&event(0, 1.050))?;
+        http.emit(&event(5, 1.040))?;
+        assert!(http.emit(&event(11, 1.030)).is_err());
+        // The retry doesn't count the failed reading twice
+        assert_eq!(http.emit(&event(11, 1.030))?, Outcome::Sent);
+        let received = requests
+            .try_iter()
+            .map(|request| serde_json::from_str(&request.body).unwrap())
+            .collect::<Vec<HashMap<String, String>>>();
+        assert_eq!(received.len(), 3);
+        assert_eq!(received[2]["gravity"], "1.035");
+        assert_eq!(received[2]["samples"], "2");
+        Ok(())
+    }
+}
@@ -1,13 +1,10 @@
-use super::{Emitter, EmitterConfig};
+use super::{Emitter, EmitterConfig, EmitterError, Outcome};
+use crate::alert::Alert;
 use crate::event::Event;
 use anyhow::Result;
 use serde::Deserialize;
-use thiserror::Error;
 use tracing::info;
 
-#[derive(Error, Debug)]
-pub enum LogError {}
-
 #[derive(Debug)]
 pub struct Log {}
 
@@ -22,8 +19,22 @@ impl EmitterConfig for LogOptions {
 }
 
 impl Emitter for Log {
-    fn emit(&self, event: &Event) -> Result<()> {
+    fn start(&self) -> Result<(), EmitterError> {
+        info!("Logging events");
+        Ok(())
+    }
+
+    fn emit(&self, event: &Event) -> Result<Outcome, EmitterError> {
         info!("Received event {:?}", event);
+        Ok(Outcome::Sent)
+    }
+
+    fn alert(&self, alert: &Alert) -> Result<(), EmitterError> {
+        info!("Received alert {:?}: {}", alert.kind, alert.message);
         Ok(())
     }
+
+    fn shutdown(&self) {
+        info!("Stopped logging events");
+    }
 }
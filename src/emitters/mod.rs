@@ -1,34 +1,267 @@
 pub mod http;
 pub mod log;
 pub mod prometheus;
+#[cfg(test)]
+pub mod test_server;
 
-use super::event::Event;
-use anyhow::Result;
+use super::event::{Color, Event};
+use crate::alert::Alert;
+use crate::batch::Batch;
+use crate::dispatcher::{ChangeDetection, Deadband, DeviceFilter, Events, Module};
+use crate::expression::{self, Expression};
+use crate::health::BreakerOptions;
+use crate::processors::{Pipeline, Sections, Stages};
+use crate::queue::Overflow;
+use crate::spool::RetryOptions;
+use crate::watchdog::DeviceStatus;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
+use std::time::Duration;
+use thiserror::Error;
 
 trait EmitterConfig {
     fn get_emitter(&self) -> Result<Box<dyn Emitter>>;
 }
 
-pub trait Emitter: Debug {
-    fn emit(&self, event: &Event) -> Result<()>;
+pub trait Emitter: Debug + Send {
+    /// Called on the emitter's own thread before it's sent anything, for
+    /// emitters that have something to set up.
+    fn start(&self) -> Result<(), EmitterError> {
+        Ok(())
+    }
+
+    /// Send a reading, or say that it's been held back, which emitters
+    /// that rate limit or need something configured may do.
+    fn emit(&self, event: &Event) -> Result<Outcome, EmitterError>;
+
+    /// Emitters that have no way to tell anyone about alerts ignore them.
+    fn alert(&self, _alert: &Alert) -> Result<(), EmitterError> {
+        Ok(())
+    }
+
+    /// Called regularly with the status of every device seen so far, even
+    /// when no readings arrive.
+    fn status(&self, _devices: &[DeviceStatus]) -> Result<(), EmitterError> {
+        Ok(())
+    }
+
+    /// Send anything the emitter has held back. Called when tilted is
+    /// stopping, after everything queued for the emitter has been sent.
+    fn flush(&self) -> Result<(), EmitterError> {
+        Ok(())
+    }
+
+    /// The readings the emitter is still holding back, to spool when they
+    /// can't be flushed.
+    fn held_back(&self) -> Vec<Event> {
+        vec![]
+    }
+
+    /// Called last, when tilted is stopping.
+    fn shutdown(&self) {}
 }
 
-pub fn init(config: &HashMap<String, Emitters>) -> Result<Vec<Box<dyn Emitter>>> {
-    let mut result: Vec<Box<dyn Emitter>> = vec![];
+/// What an emitter did with a reading it didn't fail to send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Sent,
+    /// Not sent, at least not yet.
+    Skipped,
+}
+
+/// Why an emitter couldn't send something, which decides what the
+/// dispatcher does about it.
+#[derive(Error, Debug)]
+pub enum EmitterError {
+    /// Might work if tried again later, like network errors and server
+    /// errors.
+    #[error("{0}")]
+    Transient(String),
+    /// The service wants us to slow down.
+    #[error("rate limited{}", .retry_after.map(|after| format!(", retry after {}", humantime::format_duration(after))).unwrap_or_default())]
+    RateLimited { retry_after: Option<Duration> },
+    /// Won't work until the credentials are fixed.
+    #[error("not authorized: {0}")]
+    Auth(String),
+    /// Something in the config, like a template, doesn't work for this
+    /// event.
+    #[error(transparent)]
+    Config(#[from] anyhow::Error),
+    /// The service doesn't want this event, and won't if it's sent again.
+    #[error("rejected: {0}")]
+    Rejected(String),
+}
 
-    for module in config.values() {
-        match module {
-            Emitters::Http(module) => module.get_emitter().map(|m| result.push(m))?,
-            Emitters::Log(module) => module.get_emitter().map(|m| result.push(m))?,
-            Emitters::Prometheus(module) => module.get_emitter().map(|m| result.push(m))?,
+impl From<ureq::Error> for EmitterError {
+    fn from(error: ureq::Error) -> EmitterError {
+        let message = error.to_string();
+        let kind = error.kind();
+        match error {
+            ureq::Error::Status(429, response) => EmitterError::RateLimited {
+                retry_after: response.header("retry-after").and_then(retry_after),
+            },
+            ureq::Error::Status(401, _) | ureq::Error::Status(403, _) => {
+                EmitterError::Auth(message)
+            }
+            ureq::Error::Status(408, _) => EmitterError::Transient(message),
+            ureq::Error::Status(status, _) if status >= 500 => EmitterError::Transient(message),
+            ureq::Error::Status(_, _) => EmitterError::Rejected(message),
+            ureq::Error::Transport(_) => match kind {
+                ureq::ErrorKind::InvalidUrl
+                | ureq::ErrorKind::UnknownScheme
+                | ureq::ErrorKind::InvalidProxyUrl => {
+                    EmitterError::Config(anyhow::anyhow!(message))
+                }
+                ureq::ErrorKind::ProxyUnauthorized => EmitterError::Auth(message),
+                _ => EmitterError::Transient(message),
+            },
         }
     }
+}
+
+impl From<serde_json::Error> for EmitterError {
+    fn from(error: serde_json::Error) -> EmitterError {
+        EmitterError::Config(error.into())
+    }
+}
+
+/// A Retry-After header, which is either a number of seconds or a date.
+fn retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+pub fn init(config: &HashMap<String, EmitterSection>, sections: &Sections) -> Result<Vec<Module>> {
+    let mut result: Vec<Module> = vec![];
+    let variables = example_variables(sections)?;
+
+    for (name, section) in config {
+        if section.queue_size == 0 {
+            bail!("The queue-size of {} must be at least 1", name);
+        }
+        section
+            .circuit_breaker
+            .validate()
+            .with_context(|| format!("Invalid circuit-breaker for {}", name))?;
+        let emitter = match &section.emitter {
+            Emitters::Http(module) => module.get_emitter()?,
+            Emitters::Log(module) => module.get_emitter()?,
+            Emitters::Prometheus(module) => module.get_emitter()?,
+        };
+        if let Some(filter) = &section.smoothing {
+            if !sections.smoothing.contains_key(filter) {
+                bail!(
+                    "{} uses the smoothing filter {}, which doesn't exist",
+                    name,
+                    filter
+                );
+            }
+        }
+        for (field, expression) in &section.fields {
+            if !expression::is_name(field) {
+                bail!("{} can't be the name of a field of {}", field, name);
+            }
+            expression
+                .check_variables(&variables)
+                .with_context(|| format!("Invalid field {} of {}", field, name))?;
+        }
+        if let Some(when) = &section.when {
+            let mut variables = variables.clone();
+            for field in section.fields.keys() {
+                variables[field.as_str()] = serde_json::Value::Null;
+            }
+            when.check_variables(&variables)
+                .with_context(|| format!("Invalid when of {}", name))?;
+        }
+        let pipeline = Pipeline::build(&section.pipeline, sections)
+            .with_context(|| format!("Invalid pipeline for {}", name))?;
+        result.push(Module {
+            name: name.clone(),
+            emitter,
+            pipeline,
+            when: section.when.clone(),
+            fields: section.fields.clone(),
+            failing: false,
+            smoothing: section.smoothing.clone(),
+            events: section.events,
+            include: section.include.clone(),
+            exclude: section.exclude.clone(),
+            changes: match (&section.deadband, section.heartbeat) {
+                (None, None) => None,
+                (deadband, heartbeat) => Some(ChangeDetection::new(deadband.clone(), heartbeat)?),
+            },
+            queue_size: section.queue_size,
+            overflow: section.overflow,
+            retry: section.retry.clone(),
+            breaker: section.circuit_breaker.clone(),
+        });
+    }
     Ok(result)
 }
 
+/// An example of the variables of an event, with every table that an
+/// event can have filled in, to check the variables expressions use.
+fn example_variables(sections: &Sections) -> Result<serde_json::Value> {
+    let mut event = Event::new(Color::Red, None, Utc::now(), 68., 1.);
+    let reading = event.raw;
+    event.unsmoothed = Some(reading);
+    event.smoothed = sections
+        .smoothing
+        .keys()
+        .map(|name| (name.clone(), reading))
+        .collect();
+    event.batch = Some(Batch {
+        name: String::new(),
+        style: None,
+        start: None,
+        end: None,
+        og: None,
+        target_fg: None,
+    });
+    Ok(event.variables()?)
+}
+
+/// A section of the config that defines an emitter. Settings that work the
+/// same for all emitters are here, the rest depend on the kind of emitter.
+#[derive(Deserialize, Debug)]
+pub struct EmitterSection {
+    smoothing: Option<String>,
+    #[serde(default)]
+    events: Events,
+    include: Option<DeviceFilter>,
+    exclude: Option<DeviceFilter>,
+    deadband: Option<Deadband>,
+    #[serde(with = "humantime_serde")]
+    #[serde(default)]
+    heartbeat: Option<Duration>,
+    #[serde(rename = "queue-size")]
+    #[serde(default = "default_queue_size")]
+    queue_size: usize,
+    #[serde(default)]
+    overflow: Overflow,
+    retry: Option<RetryOptions>,
+    #[serde(rename = "circuit-breaker")]
+    #[serde(default)]
+    circuit_breaker: BreakerOptions,
+    #[serde(default)]
+    pipeline: Vec<Stages>,
+    when: Option<Expression>,
+    #[serde(default)]
+    fields: BTreeMap<String, Expression>,
+    #[serde(flatten)]
+    emitter: Emitters,
+}
+
+fn default_queue_size() -> usize {
+    1000
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 #[serde(tag = "emitter")]
@@ -40,3 +273,77 @@ pub enum Emitters {
     #[serde(rename = "prometheus")]
     Prometheus(crate::emitters::prometheus::PrometheusOptions),
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn errors() {
+        let (url, _requests) = test_server::serve(vec![503, 429, 401, 400]);
+        let agent = ureq::agent();
+        let send = || EmitterError::from(agent.post(&url).send_string("").unwrap_err());
+        assert!(matches!(send(), EmitterError::Transient(_)));
+        assert!(matches!(
+            send(),
+            EmitterError::RateLimited { retry_after: None }
+        ));
+        assert!(matches!(send(), EmitterError::Auth(_)));
+        assert!(matches!(send(), EmitterError::Rejected(_)));
+        assert_eq!(retry_after("120"), Some(Duration::from_secs(120)));
+        let later = (Utc::now() + chrono::Duration::minutes(10)).to_rfc2822();
+        assert!(retry_after(&later).unwrap() > Duration::from_secs(9 * 60));
+    }
+
+    #[test]
+    fn variables() -> Result<()> {
+        let sections = Sections {
+            smoothing: toml::from_str("[average]\nfilter = \"moving-average\"\nwindow = 2\n")?,
+            ..Default::default()
+        };
+        let init = |when: &str| {
+            let section: EmitterSection = toml::from_str(&format!(
+                "emitter = \"log\"\nwhen = {:?}\nfields = {{ batch = \"1\" }}\n",
+                when
+            ))
+            .unwrap();
+            super::init(
+                &vec![("log".to_string(), section)].into_iter().collect(),
+                &sections,
+            )
+            .map_err(|e| format!("{:#}", e))
+        };
+        assert!(init("smoothed.average.gravity < 1.02 && unsmoothed.gravity > 1").is_ok());
+        // A field replaces the variable of the same name
+        assert!(init("batch == 1").is_ok());
+        assert!(init("batch.name == 'IPA'")
+            .unwrap_err()
+            .contains("Unknown variable batch.name"));
+        assert!(init("smoothed.kalman.gravity < 1.02")
+            .unwrap_err()
+            .contains("Unknown variable smoothed.kalman"));
+        assert!(init("raw == null").unwrap_err().contains("raw is a table"));
+        Ok(())
+    }
+
+    #[test]
+    fn circuit_breaker() {
+        let init = |circuit_breaker: &str| {
+            let section: EmitterSection = toml::from_str(&format!(
+                "emitter = \"log\"\ncircuit-breaker = {}\n",
+                circuit_breaker
+            ))
+            .unwrap();
+            super::init(
+                &vec![("log".to_string(), section)].into_iter().collect(),
+                &Default::default(),
+            )
+            .map_err(|e| format!("{:#}", e))
+        };
+        assert!(init("{ failures = 1 }").is_ok());
+        assert_eq!(
+            init("{ failures = 0 }").unwrap_err(),
+            "Invalid circuit-breaker for log: failures must be at least 1"
+        );
+    }
+}
@@ -1,17 +1,28 @@
-use super::{Emitter, EmitterConfig};
+use super::{Emitter, EmitterConfig, EmitterError, Outcome};
 use crate::event::Event;
+use crate::units::{GravityUnit, TemperatureUnit};
+use crate::watchdog::DeviceStatus;
 use anyhow::Result;
+use chrono::Utc;
 use serde::Deserialize;
-use thiserror::Error;
-
-#[derive(Error, Debug)]
-pub enum PrometheusError {}
+use std::time::Duration;
 
 #[derive(Debug)]
 pub struct Prometheus {
+    agent: ureq::Agent,
     address: String,
     temp_gauge_name: String,
     gravity_gauge_name: String,
+    timestamp_gauge_name: Option<String>,
+    outliers_gauge_name: Option<String>,
+    og_gauge_name: Option<String>,
+    abv_gauge_name: Option<String>,
+    attenuation_gauge_name: Option<String>,
+    gravity_rate_gauge_name: Option<String>,
+    last_seen_gauge_name: Option<String>,
+    device_label: bool,
+    temperature_unit: TemperatureUnit,
+    gravity_unit: GravityUnit,
 }
 
 #[derive(Deserialize, Debug)]
@@ -20,31 +31,175 @@ pub struct PrometheusOptions {
     address: String,
     temp_gauge_name: String,
     gravity_gauge_name: String,
+    timestamp_gauge_name: Option<String>,
+    outliers_gauge_name: Option<String>,
+    og_gauge_name: Option<String>,
+    abv_gauge_name: Option<String>,
+    attenuation_gauge_name: Option<String>,
+    gravity_rate_gauge_name: Option<String>,
+    last_seen_gauge_name: Option<String>,
+    #[serde(default)]
+    device_label: bool,
+    #[serde(default)]
+    temperature_unit: TemperatureUnit,
+    #[serde(default)]
+    gravity_unit: GravityUnit,
+    #[serde(with = "humantime_serde")]
+    #[serde(default = "default_timeout")]
+    timeout: Duration,
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(30)
 }
 
 impl EmitterConfig for PrometheusOptions {
     fn get_emitter(&self) -> Result<Box<dyn Emitter>> {
         let p = Prometheus {
+            agent: ureq::AgentBuilder::new().timeout(self.timeout).build(),
             address: self.address.to_string(),
             temp_gauge_name: self.temp_gauge_name.clone(),
             gravity_gauge_name: self.gravity_gauge_name.clone(),
+            timestamp_gauge_name: self.timestamp_gauge_name.clone(),
+            outliers_gauge_name: self.outliers_gauge_name.clone(),
+            og_gauge_name: self.og_gauge_name.clone(),
+            abv_gauge_name: self.abv_gauge_name.clone(),
+            attenuation_gauge_name: self.attenuation_gauge_name.clone(),
+            gravity_rate_gauge_name: self.gravity_rate_gauge_name.clone(),
+            last_seen_gauge_name: self.last_seen_gauge_name.clone(),
+            device_label: self.device_label,
+            temperature_unit: self.temperature_unit,
+            gravity_unit: self.gravity_unit,
         };
         Ok(Box::new(p))
     }
 }
 
-impl Emitter for Prometheus {
-    fn emit(&self, event: &Event) -> Result<()> {
+impl Prometheus {
+    /// The labels of every gauge. Besides the color, the device tells apart
+    /// tilts of the same color, if it's turned on.
+    fn labels(&self, event: &Event) -> String {
         let color: &'static str = (&event.color).into();
+        if self.device_label {
+            format!("color=\"{}\",device=\"{}\"", color, event.device())
+        } else {
+            format!("color=\"{}\"", color)
+        }
+    }
+}
+
+impl Emitter for Prometheus {
+    fn emit(&self, event: &Event) -> Result<Outcome, EmitterError> {
+        let labels = self.labels(event);
         let address = format!("{}/metrics/jobs/{}", self.address, "tilted");
-        ureq::post(&address).send_string(&format!(
-            "{}{{color={}}} {}",
-            self.temp_gauge_name, color, event.temperature
+        // The units of a convert stage win over the emitter's own
+        let temperature_unit = event.temperature_unit.unwrap_or(self.temperature_unit);
+        let gravity_unit = event.gravity_unit.unwrap_or(self.gravity_unit);
+        self.agent.post(&address).send_string(&format!(
+            "{}{{{}}} {}",
+            self.temp_gauge_name,
+            labels,
+            temperature_unit.convert(event.temperature)
         ))?;
-        ureq::post(&address).send_string(&format!(
-            "{}{{color={}}} {}",
-            self.gravity_gauge_name, color, event.gravity
+        self.agent.post(&address).send_string(&format!(
+            "{}{{{}}} {}",
+            self.gravity_gauge_name,
+            labels,
+            gravity_unit.convert(event.gravity)
         ))?;
+        if let Some(timestamp_gauge_name) = &self.timestamp_gauge_name {
+            self.agent.post(&address).send_string(&format!(
+                "{}{{{}}} {}",
+                timestamp_gauge_name,
+                labels,
+                event.timestamp.timestamp()
+            ))?;
+        }
+        if let Some(outliers_gauge_name) = &self.outliers_gauge_name {
+            self.agent.post(&address).send_string(&format!(
+                "{}{{{}}} {}",
+                outliers_gauge_name, labels, event.outliers
+            ))?;
+        }
+        // These aren't known for every event, so only push the ones that are
+        let derived = [
+            (
+                &self.og_gauge_name,
+                event.og.map(|og| gravity_unit.convert(og)),
+            ),
+            (&self.abv_gauge_name, event.abv),
+            (&self.attenuation_gauge_name, event.attenuation),
+            // In SG per day whatever the unit, since a rate doesn't convert
+            // like a gravity does
+            (&self.gravity_rate_gauge_name, event.gravity_rate),
+        ];
+        for (gauge_name, value) in &derived {
+            if let (Some(gauge_name), Some(value)) = (gauge_name, value) {
+                self.agent
+                    .post(&address)
+                    .send_string(&format!("{}{{{}}} {}", gauge_name, labels, value))?;
+            }
+        }
+        Ok(Outcome::Sent)
+    }
+
+    fn status(&self, devices: &[DeviceStatus]) -> Result<(), EmitterError> {
+        let last_seen_gauge_name = match &self.last_seen_gauge_name {
+            Some(last_seen_gauge_name) => last_seen_gauge_name,
+            None => return Ok(()),
+        };
+        let address = format!("{}/metrics/jobs/{}", self.address, "tilted");
+        let now = Utc::now();
+        for device in devices {
+            self.agent.post(&address).send_string(&format!(
+                "{}{{{}}} {}",
+                last_seen_gauge_name,
+                self.labels(&device.last),
+                device.age(&now).as_secs()
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emitters::test_server::serve;
+    use crate::event::Color;
+
+    #[test]
+    fn last_seen() -> Result<()> {
+        let (url, requests) = serve(vec![]);
+        let prometheus = |device_label| -> Result<Box<dyn Emitter>> {
+            let options: PrometheusOptions = toml::from_str(&format!(
+                r#"address = "{}"
+temp_gauge_name = "temperature"
+gravity_gauge_name = "gravity"
+last_seen_gauge_name = "last_seen"
+device_label = {}
+"#,
+                url, device_label
+            ))?;
+            options.get_emitter()
+        };
+        let now = Utc::now();
+        let device = |mac: &str| DeviceStatus {
+            last: Event::new(Color::Red, Some(mac.to_string()), now, 68., 1.05),
+            last_seen: now,
+        };
+        prometheus(false)?.status(&[device("AA:BB:CC:DD:EE:01")])?;
+        assert_eq!(requests.recv()?.body, "last_seen{color=\"red\"} 0");
+
+        prometheus(true)?.status(&[device("AA:BB:CC:DD:EE:01"), device("AA:BB:CC:DD:EE:02")])?;
+        assert_eq!(
+            requests.recv()?.body,
+            "last_seen{color=\"red\",device=\"AA:BB:CC:DD:EE:01\"} 0"
+        );
+        assert_eq!(
+            requests.recv()?.body,
+            "last_seen{color=\"red\",device=\"AA:BB:CC:DD:EE:02\"} 0"
+        );
         Ok(())
     }
 }
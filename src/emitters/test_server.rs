@@ -0,0 +1,65 @@
+//! A tiny HTTP server for testing emitters against.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+/// A request the server received.
+#[derive(Debug)]
+pub struct Request {
+    pub body: String,
+}
+
+/// Serve on a random port, answering every request with the next status
+/// in `statuses`, and 200 once they run out. Returns the address and the
+/// requests the server receives.
+pub fn serve(statuses: Vec<u16>) -> (String, Receiver<Request>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = format!("http://{}", listener.local_addr().unwrap());
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        let mut statuses = statuses.into_iter();
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => return,
+            };
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            // Clients may send several requests over the same connection
+            loop {
+                let mut request_line = String::new();
+                if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                    break;
+                }
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    let header = header.trim();
+                    if header.is_empty() {
+                        break;
+                    }
+                    let (name, value) = header.split_once(':').unwrap();
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                // Before answering, so the request is there as soon as the
+                // client is done
+                let body = String::from_utf8(body).unwrap();
+                if sender.send(Request { body }).is_err() {
+                    return;
+                }
+                let status = statuses.next().unwrap_or(200);
+                let response = format!("HTTP/1.1 {} Status\r\nContent-Length: 0\r\n\r\n", status);
+                if stream.write_all(response.as_bytes()).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    (address, receiver)
+}
@@ -1,15 +1,150 @@
-use crate::emitters::Emitter;
-use serde::Serialize;
-use tracing::warn;
+use crate::alert::Alert;
+use crate::batch::Batch;
+use crate::units::{self, round, GravityUnit, TemperatureUnit};
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, Utc,
+};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Event {
     pub color: Color,
-    pub temperature: u16, // Farenheight
-    pub gravity: f32,
+    pub mac: Option<String>,
+    pub name: Option<String>,     // From the device's config, if it has one
+    pub timestamp: DateTime<Utc>, // When the reading was taken
+    pub temperature: f64,         // Farenheight
+    pub gravity: f64,
+    pub raw: Reading, // As reported by the device, before any calibration
+    pub uncorrected_gravity: Option<f64>, // Before temperature correction, if any
+    pub smoothed: BTreeMap<String, Reading>, // By the name of the smoothing filter
+    pub unsmoothed: Option<Reading>, // If an emitter uses smoothed values instead
+    pub outlier: bool, // Only ever true if outliers are tagged
+    pub outliers: u64, // Readings from this device rejected so far
+    pub og: Option<f64>, // Configured, or detected from the first stable readings
+    pub abv: Option<f64>, // In percent
+    pub attenuation: Option<f64>, // Apparent attenuation, in percent
+    pub gravity_rate: Option<f64>, // Change in gravity per day
+    pub batch: Option<Batch>, // The batch in the device, if any
+    pub temperature_unit: Option<TemperatureUnit>, // To send the temperature in, if converted
+    pub gravity_unit: Option<GravityUnit>, // To send the gravity in, if converted
+    #[serde(default)]
+    pub fields: BTreeMap<String, serde_json::Value>, // Computed by the emitter's `fields`
+    #[serde(skip)]
+    pub alerts: Vec<Alert>, // Caused by this reading, sent after it
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Reading {
+    pub temperature: f64,
+    pub gravity: f64,
+}
+
+impl Event {
+    pub fn new(
+        color: Color,
+        mac: Option<String>,
+        timestamp: DateTime<Utc>,
+        temperature: f64,
+        gravity: f64,
+    ) -> Event {
+        Event {
+            color,
+            mac,
+            name: None,
+            timestamp,
+            temperature,
+            gravity,
+            raw: Reading {
+                temperature,
+                gravity,
+            },
+            uncorrected_gravity: None,
+            smoothed: BTreeMap::new(),
+            unsmoothed: None,
+            outlier: false,
+            outliers: 0,
+            og: None,
+            abv: None,
+            attenuation: None,
+            gravity_rate: None,
+            batch: None,
+            temperature_unit: None,
+            gravity_unit: None,
+            fields: BTreeMap::new(),
+            alerts: vec![],
+        }
+    }
+
+    /// Identifies the device across events, for keeping track of state
+    /// per device.
+    pub fn device(&self) -> String {
+        match &self.mac {
+            Some(mac) => mac.clone(),
+            None => <&'static str>::from(&self.color).to_string(),
+        }
+    }
+
+    /// The variables available to templates and expressions, in the
+    /// units of the event.
+    pub fn variables(&self) -> serde_json::Result<serde_json::Value> {
+        let mut variables = serde_json::to_value(self)?;
+        if let Some(variables) = variables.as_object_mut() {
+            variables.remove("fields");
+            variables.remove("temperature_unit");
+            variables.remove("gravity_unit");
+        }
+        variables["fahrenheit"] = variables["temperature"].clone();
+        variables["celsius"] = round(units::fahrenheit_to_celsius(self.temperature), 2).into();
+        variables["sg"] = variables["gravity"].clone();
+        variables["plato"] = round(units::sg_to_plato(self.gravity), 2).into();
+        variables["brix"] = round(units::sg_to_brix(self.gravity), 2).into();
+        variables["uncorrected_gravity"] = self.uncorrected_gravity.unwrap_or(self.gravity).into();
+        for (name, value) in &self.fields {
+            variables[name.as_str()] = value.clone();
+        }
+        Ok(variables)
+    }
+}
+
+/// How to render an event's timestamp for a service.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum TimestampFormat {
+    #[default]
+    Rfc3339,
+    Epoch,
+    EpochMillis,
+    Strftime(String),
+}
+
+impl TimestampFormat {
+    pub fn format(&self, timestamp: &DateTime<Utc>) -> String {
+        match self {
+            TimestampFormat::Rfc3339 => timestamp.to_rfc3339(),
+            TimestampFormat::Epoch => timestamp.timestamp().to_string(),
+            TimestampFormat::EpochMillis => timestamp.timestamp_millis().to_string(),
+            TimestampFormat::Strftime(format) => timestamp.format(format).to_string(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TimestampFormat {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<TimestampFormat, D::Error> {
+        let format = String::deserialize(deserializer)?;
+        match format.as_ref() {
+            "rfc3339" => Ok(TimestampFormat::Rfc3339),
+            "epoch" => Ok(TimestampFormat::Epoch),
+            "epoch-millis" => Ok(TimestampFormat::EpochMillis),
+            _ if StrftimeItems::new(&format).any(|item| item == Item::Error) => Err(
+                de::Error::custom(format!("invalid timestamp format {:?}", format)),
+            ),
+            _ => Ok(TimestampFormat::Strftime(format)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Color {
     Red,
@@ -37,16 +172,44 @@ impl<'a> From<&'a Color> for &'static str {
     }
 }
 
-pub struct Dispatcher {
-    pub modules: Vec<Box<dyn Emitter>>,
-}
+impl std::str::FromStr for Color {
+    type Err = String;
 
-impl Dispatcher {
-    pub fn dispatch(&self, event: &Event) {
-        for module in &self.modules {
-            if let Err(e) = module.emit(event) {
-                warn!("Error emitting event {}", e);
-            }
+    fn from_str(color: &str) -> Result<Color, String> {
+        match color.to_lowercase().as_ref() {
+            "red" => Ok(Color::Red),
+            "green" => Ok(Color::Green),
+            "black" => Ok(Color::Black),
+            "purple" => Ok(Color::Purple),
+            "orange" => Ok(Color::Orange),
+            "blue" => Ok(Color::Blue),
+            "yellow" => Ok(Color::Yellow),
+            "pink" => Ok(Color::Pink),
+            _ => Err(format!("{} is not a tilt color", color)),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn timestamp_formats() {
+        let timestamp = Utc.timestamp_millis_opt(1_600_000_000_123).unwrap();
+        assert_eq!(
+            TimestampFormat::Rfc3339.format(&timestamp),
+            "2020-09-13T12:26:40.123+00:00"
+        );
+        assert_eq!(TimestampFormat::Epoch.format(&timestamp), "1600000000");
+        assert_eq!(
+            TimestampFormat::EpochMillis.format(&timestamp),
+            "1600000000123"
+        );
+        assert_eq!(
+            TimestampFormat::Strftime("%d/%m/%Y %H:%M".to_string()).format(&timestamp),
+            "13/09/2020 12:26"
+        );
+    }
+}
@@ -0,0 +1,647 @@
+use crate::units::round;
+use anyhow::{bail, Result};
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_while},
+    character::complete::{alpha1, alphanumeric1, char, digit0, digit1, multispace0, one_of},
+    combinator::{cut, eof, map, opt, recognize},
+    error::{context, VerboseError, VerboseErrorKind},
+    multi::{many0, separated_list0},
+    sequence::{delimited, pair, preceded, terminated, tuple},
+    Err,
+};
+use serde::{de, Deserialize, Deserializer};
+use std::fmt;
+use thiserror::Error;
+
+type IResult<'a, T> = nom::IResult<&'a str, T, VerboseError<&'a str>>;
+
+/// A small expression, like `gravity < 1.020 && color == 'red'`, that's
+/// worked out from the variables of an event.
+#[derive(Debug, Clone)]
+pub struct Expression {
+    source: String,
+    node: Node,
+}
+
+/// A problem with an expression, found when the config is loaded.
+#[derive(Error, Debug)]
+#[error("{message} at position {position} in {expression:?}")]
+pub struct ExpressionError {
+    expression: String,
+    position: usize,
+    message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Literal(Value),
+    /// A dotted path, like `smoothed.average.gravity`, starting at byte
+    /// `at` of the source
+    Variable {
+        path: Vec<String>,
+        at: usize,
+    },
+    Call {
+        function: String,
+        arguments: Vec<Node>,
+        at: usize,
+    },
+    Not(Box<Node>),
+    Negate(Box<Node>),
+    Binary(Operator, Box<Node>, Box<Node>),
+}
+
+/// What an expression works with and results in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(f64),
+    String(String),
+    Bool(bool),
+    Null,
+}
+
+impl Value {
+    fn from_json(name: &str, value: &serde_json::Value) -> Result<Value> {
+        Ok(match value {
+            serde_json::Value::Null => Value::Null,
+            serde_json::Value::Bool(value) => Value::Bool(*value),
+            serde_json::Value::Number(value) => Value::Number(value.as_f64().unwrap_or(f64::NAN)),
+            serde_json::Value::String(value) => Value::String(value.clone()),
+            _ => bail!("{} isn't a single value", name),
+        })
+    }
+
+    /// Whether a condition holds. Missing values count as false.
+    fn is_true(&self) -> Result<bool> {
+        match self {
+            Value::Bool(value) => Ok(*value),
+            Value::Null => Ok(false),
+            _ => bail!("{} isn't true or false", self),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Number(value) => write!(f, "{}", value),
+            Value::String(value) => write!(f, "'{}'", value),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Null => write!(f, "null"),
+        }
+    }
+}
+
+impl From<Value> for serde_json::Value {
+    fn from(value: Value) -> serde_json::Value {
+        match value {
+            Value::Number(value) => serde_json::Number::from_f64(value)
+                .map(serde_json::Value::Number)
+                .unwrap_or(serde_json::Value::Null),
+            Value::String(value) => value.into(),
+            Value::Bool(value) => value.into(),
+            Value::Null => serde_json::Value::Null,
+        }
+    }
+}
+
+/// The functions expressions can call, and how many arguments they take.
+const FUNCTIONS: &[(&str, usize, usize)] = &[
+    ("abs", 1, 1),
+    ("round", 1, 2),
+    ("min", 2, usize::MAX),
+    ("max", 2, usize::MAX),
+    ("if", 3, 3),
+];
+
+/// Parses an expression, keeping track of where in it each part is.
+struct Parser<'a> {
+    source: &'a str,
+}
+
+fn ws<'a, T>(
+    parser: impl FnMut(&'a str) -> IResult<'a, T>,
+) -> impl FnMut(&'a str) -> IResult<'a, T> {
+    delimited(multispace0, parser, multispace0)
+}
+
+fn identifier(input: &str) -> IResult<'_, &str> {
+    recognize(pair(
+        alt((alpha1, tag("_"))),
+        many0(alt((alphanumeric1, tag("_")))),
+    ))(input)
+}
+
+fn number(input: &str) -> IResult<'_, Value> {
+    map(
+        recognize(tuple((
+            digit1,
+            opt(pair(char('.'), digit0)),
+            opt(tuple((one_of("eE"), opt(one_of("+-")), cut(digit1)))),
+        ))),
+        |number: &str| Value::Number(number.parse().unwrap_or(f64::NAN)),
+    )(input)
+}
+
+fn string(input: &str) -> IResult<'_, Value> {
+    let quoted = |quote| {
+        preceded(
+            char(quote),
+            cut(terminated(
+                take_while(move |c| c != quote),
+                context("the end of the string", char(quote)),
+            )),
+        )
+    };
+    map(alt((quoted('\''), quoted('"'))), |string: &str| {
+        Value::String(string.to_string())
+    })(input)
+}
+
+/// Whether `name` can be used as a variable.
+pub fn is_name(name: &str) -> bool {
+    identifier(name).is_ok_and(|(rest, _)| rest.is_empty())
+}
+
+impl<'a> Parser<'a> {
+    fn at(&self, input: &str) -> usize {
+        self.source.len() - input.len()
+    }
+
+    fn parse(&self) -> IResult<'a, Node> {
+        terminated(
+            preceded(multispace0, |i| self.or(i)),
+            context("an operator", eof),
+        )(self.source)
+    }
+
+    /// Operands separated by any of `operators`, which are all of the same
+    /// precedence and left associative.
+    fn binary(
+        &self,
+        input: &'a str,
+        operators: &[(&'static str, Operator)],
+        operand: fn(&Self, &'a str) -> IResult<'a, Node>,
+    ) -> IResult<'a, Node> {
+        let (mut input, mut node) = operand(self, input)?;
+        'next: loop {
+            for (symbol, operator) in operators {
+                if let Ok((rest, _)) = ws(tag::<_, _, VerboseError<&str>>(*symbol))(input) {
+                    let (rest, right) = cut(|i| operand(self, i))(rest)?;
+                    node = Node::Binary(*operator, Box::new(node), Box::new(right));
+                    input = rest;
+                    continue 'next;
+                }
+            }
+            return Ok((input, node));
+        }
+    }
+
+    fn or(&self, input: &'a str) -> IResult<'a, Node> {
+        self.binary(input, &[("||", Operator::Or)], Parser::and)
+    }
+
+    fn and(&self, input: &'a str) -> IResult<'a, Node> {
+        self.binary(input, &[("&&", Operator::And)], Parser::comparison)
+    }
+
+    fn comparison(&self, input: &'a str) -> IResult<'a, Node> {
+        // The longer operators first, so `<=` isn't taken for `<`
+        self.binary(
+            input,
+            &[
+                ("==", Operator::Equal),
+                ("!=", Operator::NotEqual),
+                ("<=", Operator::LessOrEqual),
+                (">=", Operator::GreaterOrEqual),
+                ("<", Operator::Less),
+                (">", Operator::Greater),
+            ],
+            Parser::sum,
+        )
+    }
+
+    fn sum(&self, input: &'a str) -> IResult<'a, Node> {
+        self.binary(
+            input,
+            &[("+", Operator::Add), ("-", Operator::Subtract)],
+            Parser::product,
+        )
+    }
+
+    fn product(&self, input: &'a str) -> IResult<'a, Node> {
+        self.binary(
+            input,
+            &[("*", Operator::Multiply), ("/", Operator::Divide)],
+            Parser::unary,
+        )
+    }
+
+    fn unary(&self, input: &'a str) -> IResult<'a, Node> {
+        alt((
+            map(preceded(ws(char('!')), cut(|i| self.unary(i))), |node| {
+                Node::Not(Box::new(node))
+            }),
+            map(preceded(ws(char('-')), cut(|i| self.unary(i))), |node| {
+                Node::Negate(Box::new(node))
+            }),
+            |i| self.atom(i),
+        ))(input)
+    }
+
+    fn atom(&self, input: &'a str) -> IResult<'a, Node> {
+        context(
+            "a value",
+            ws(alt((
+                map(number, Node::Literal),
+                map(string, Node::Literal),
+                delimited(
+                    char('('),
+                    cut(|i| self.or(i)),
+                    cut(context("`)`", char(')'))),
+                ),
+                |i| self.name(i),
+            ))),
+        )(input)
+    }
+
+    /// A keyword, a function call, or a variable.
+    fn name(&self, input: &'a str) -> IResult<'a, Node> {
+        let at = self.at(input);
+        let (rest, name) = identifier(input)?;
+        if let Ok((rest, _)) = ws(char::<_, VerboseError<&str>>('('))(rest) {
+            let (rest, arguments) = cut(terminated(
+                separated_list0(ws(char(',')), |i| self.or(i)),
+                context("`)`", char(')')),
+            ))(rest)?;
+            return Ok((
+                rest,
+                Node::Call {
+                    function: name.to_string(),
+                    arguments,
+                    at,
+                },
+            ));
+        }
+        let literal = match name {
+            "true" => Some(Value::Bool(true)),
+            "false" => Some(Value::Bool(false)),
+            "null" => Some(Value::Null),
+            _ => None,
+        };
+        if let Some(literal) = literal {
+            return Ok((rest, Node::Literal(literal)));
+        }
+        let (rest, path) = many0(preceded(char('.'), cut(context("a name", identifier))))(rest)?;
+        let path = std::iter::once(name)
+            .chain(path)
+            .map(String::from)
+            .collect();
+        Ok((rest, Node::Variable { path, at }))
+    }
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Expression, ExpressionError> {
+        let parser = Parser { source };
+        let node = match parser.parse() {
+            Ok((_, node)) => node,
+            Err(Err::Error(error)) | Err(Err::Failure(error)) => {
+                let (input, _) = error.errors[0];
+                let message = match error.errors.iter().find_map(|(_, kind)| match kind {
+                    VerboseErrorKind::Context(context) => Some(context),
+                    _ => None,
+                }) {
+                    Some(expected) => format!("Expected {}", expected),
+                    None => "Syntax error".to_string(),
+                };
+                return Err(ExpressionError::new(source, parser.at(input), message));
+            }
+            Err(Err::Incomplete(_)) => unreachable!("only complete parsers are used"),
+        };
+        let expression = Expression {
+            source: source.to_string(),
+            node,
+        };
+        expression.check_functions(&expression.node)?;
+        Ok(expression)
+    }
+
+    fn check_functions(&self, node: &Node) -> Result<(), ExpressionError> {
+        match node {
+            Node::Call {
+                function,
+                arguments,
+                at,
+            } => {
+                let (_, min, max) = FUNCTIONS
+                    .iter()
+                    .find(|(name, _, _)| name == function)
+                    .ok_or_else(|| {
+                        ExpressionError::new(
+                            &self.source,
+                            *at,
+                            format!("Unknown function {}", function),
+                        )
+                    })?;
+                if arguments.len() < *min || arguments.len() > *max {
+                    return Err(ExpressionError::new(
+                        &self.source,
+                        *at,
+                        format!("Wrong number of arguments to {}", function),
+                    ));
+                }
+                arguments
+                    .iter()
+                    .try_for_each(|argument| self.check_functions(argument))
+            }
+            Node::Not(node) | Node::Negate(node) => self.check_functions(node),
+            Node::Binary(_, left, right) => {
+                self.check_functions(left)?;
+                self.check_functions(right)
+            }
+            Node::Literal(_) | Node::Variable { .. } => Ok(()),
+        }
+    }
+
+    /// Make sure every variable is in `variables`, an example of the
+    /// variables of an event with every table filled in, since a typo
+    /// would otherwise quietly be `null`. Tables, like `batch`, aren't
+    /// values themselves, so only their fields can be used.
+    pub fn check_variables(&self, variables: &serde_json::Value) -> Result<(), ExpressionError> {
+        self.check_node(&self.node, variables)
+    }
+
+    fn check_node(
+        &self,
+        node: &Node,
+        variables: &serde_json::Value,
+    ) -> Result<(), ExpressionError> {
+        match node {
+            Node::Variable { path, at } => {
+                let mut value = variables;
+                for (i, name) in path.iter().enumerate() {
+                    value = value.get(name).ok_or_else(|| {
+                        ExpressionError::new(
+                            &self.source,
+                            *at,
+                            format!("Unknown variable {}", path[..=i].join(".")),
+                        )
+                    })?;
+                }
+                if value.is_object() {
+                    return Err(ExpressionError::new(
+                        &self.source,
+                        *at,
+                        format!("{} is a table, so use one of its fields", path.join(".")),
+                    ));
+                }
+                Ok(())
+            }
+            Node::Call { arguments, .. } => arguments
+                .iter()
+                .try_for_each(|argument| self.check_node(argument, variables)),
+            Node::Not(node) | Node::Negate(node) => self.check_node(node, variables),
+            Node::Binary(_, left, right) => {
+                self.check_node(left, variables)?;
+                self.check_node(right, variables)
+            }
+            Node::Literal(_) => Ok(()),
+        }
+    }
+
+    /// Work out the value of the expression from some variables.
+    pub fn evaluate(&self, variables: &serde_json::Value) -> Result<Value> {
+        evaluate(&self.node, variables)
+    }
+
+    /// Whether the expression is true for some variables.
+    pub fn is_true(&self, variables: &serde_json::Value) -> Result<bool> {
+        self.evaluate(variables)?.is_true()
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl ExpressionError {
+    fn new(source: &str, at: usize, message: String) -> ExpressionError {
+        ExpressionError {
+            expression: source.to_string(),
+            position: source[..at].chars().count() + 1,
+            message,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Expression {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Expression, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Expression::parse(&source).map_err(de::Error::custom)
+    }
+}
+
+fn evaluate(node: &Node, variables: &serde_json::Value) -> Result<Value> {
+    Ok(match node {
+        Node::Literal(value) => value.clone(),
+        Node::Variable { path, .. } => {
+            let value = path
+                .iter()
+                .try_fold(variables, |value, name| value.get(name))
+                .unwrap_or(&serde_json::Value::Null);
+            Value::from_json(&path.join("."), value)?
+        }
+        Node::Not(node) => Value::Bool(!evaluate(node, variables)?.is_true()?),
+        Node::Negate(node) => match evaluate(node, variables)? {
+            Value::Number(value) => Value::Number(-value),
+            Value::Null => Value::Null,
+            value => bail!("Can't negate {}", value),
+        },
+        // Only work out the right hand side if it's needed
+        Node::Binary(Operator::And, left, right) => Value::Bool(
+            evaluate(left, variables)?.is_true()? && evaluate(right, variables)?.is_true()?,
+        ),
+        Node::Binary(Operator::Or, left, right) => Value::Bool(
+            evaluate(left, variables)?.is_true()? || evaluate(right, variables)?.is_true()?,
+        ),
+        Node::Binary(operator, left, right) => binary(
+            *operator,
+            evaluate(left, variables)?,
+            evaluate(right, variables)?,
+        )?,
+        Node::Call {
+            function,
+            arguments,
+            ..
+        } => {
+            if function == "if" {
+                let branch = if evaluate(&arguments[0], variables)?.is_true()? {
+                    &arguments[1]
+                } else {
+                    &arguments[2]
+                };
+                return evaluate(branch, variables);
+            }
+            let mut numbers = vec![];
+            for argument in arguments {
+                match evaluate(argument, variables)? {
+                    Value::Number(value) => numbers.push(value),
+                    // Missing values make the result missing
+                    Value::Null => return Ok(Value::Null),
+                    value => bail!("{} needs numbers, not {}", function, value),
+                }
+            }
+            Value::Number(match function.as_ref() {
+                "abs" => numbers[0].abs(),
+                "round" => round(numbers[0], *numbers.get(1).unwrap_or(&0.) as i32),
+                "min" => numbers.into_iter().fold(f64::INFINITY, f64::min),
+                "max" => numbers.into_iter().fold(f64::NEG_INFINITY, f64::max),
+                _ => unreachable!("functions are checked when parsed"),
+            })
+        }
+    })
+}
+
+fn binary(operator: Operator, left: Value, right: Value) -> Result<Value> {
+    use Operator::*;
+    Ok(match (operator, left, right) {
+        (Equal, left, right) => Value::Bool(left == right),
+        (NotEqual, left, right) => Value::Bool(left != right),
+        // Missing values compare as false, and make arithmetic missing
+        (Less | LessOrEqual | Greater | GreaterOrEqual, Value::Null, _)
+        | (Less | LessOrEqual | Greater | GreaterOrEqual, _, Value::Null) => Value::Bool(false),
+        (_, Value::Null, _) | (_, _, Value::Null) => Value::Null,
+        (Less, Value::Number(left), Value::Number(right)) => Value::Bool(left < right),
+        (LessOrEqual, Value::Number(left), Value::Number(right)) => Value::Bool(left <= right),
+        (Greater, Value::Number(left), Value::Number(right)) => Value::Bool(left > right),
+        (GreaterOrEqual, Value::Number(left), Value::Number(right)) => Value::Bool(left >= right),
+        (Less, Value::String(left), Value::String(right)) => Value::Bool(left < right),
+        (LessOrEqual, Value::String(left), Value::String(right)) => Value::Bool(left <= right),
+        (Greater, Value::String(left), Value::String(right)) => Value::Bool(left > right),
+        (GreaterOrEqual, Value::String(left), Value::String(right)) => Value::Bool(left >= right),
+        (Add, Value::Number(left), Value::Number(right)) => Value::Number(left + right),
+        (Add, Value::String(left), Value::String(right)) => Value::String(left + &right),
+        (Subtract, Value::Number(left), Value::Number(right)) => Value::Number(left - right),
+        (Multiply, Value::Number(left), Value::Number(right)) => Value::Number(left * right),
+        (Divide, Value::Number(left), Value::Number(right)) => Value::Number(left / right),
+        (operator, left, right) => bail!("Can't use {:?} on {} and {}", operator, left, right),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn evaluate() -> Result<()> {
+        let variables = json!({
+            "gravity": 1.018,
+            "color": "red",
+            "og": null,
+            "smoothed": { "average": { "gravity": 1.02 } },
+        });
+        let evaluate = |source| Expression::parse(source).unwrap().evaluate(&variables);
+        assert_eq!(
+            evaluate("gravity < 1.020 && color == 'red'")?,
+            Value::Bool(true)
+        );
+        assert_eq!(
+            evaluate("round(259 - 259 / gravity, 2)")?,
+            Value::Number(4.58)
+        );
+        assert_eq!(evaluate("1 + 2 * 3 - -4 / 2")?, Value::Number(9.));
+        assert_eq!(evaluate("(1 + 2) * 3")?, Value::Number(9.));
+        assert_eq!(
+            evaluate("smoothed.average.gravity >= 1.02")?,
+            Value::Bool(true)
+        );
+        assert_eq!(evaluate("og - gravity")?, Value::Null);
+        assert_eq!(evaluate("og > 1 || !(og != null)")?, Value::Bool(true));
+        assert_eq!(
+            evaluate("if(gravity < 1.02, 'done', \"fermenting\")")?,
+            Value::String("done".to_string())
+        );
+        assert_eq!(evaluate("max(1, gravity, 2e-3)")?, Value::Number(1.018));
+        assert!(evaluate("color + 1").is_err());
+        assert!(evaluate("smoothed").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source| Expression::parse(source).unwrap_err().to_string();
+        assert_eq!(
+            error("gravity < "),
+            "Expected a value at position 11 in \"gravity < \""
+        );
+        assert_eq!(
+            error("(gravity < 1"),
+            "Expected `)` at position 13 in \"(gravity < 1\""
+        );
+        assert_eq!(
+            error("gravity 1"),
+            "Expected an operator at position 9 in \"gravity 1\""
+        );
+        assert_eq!(
+            error("color == 'red"),
+            "Expected the end of the string at position 14 in \"color == 'red\""
+        );
+        assert_eq!(
+            error("1 + sqrt(gravity)"),
+            "Unknown function sqrt at position 5 in \"1 + sqrt(gravity)\""
+        );
+        assert_eq!(
+            error("round()"),
+            "Wrong number of arguments to round at position 1 in \"round()\""
+        );
+        let variables = json!({
+            "gravity": 1.05,
+            "color": "red",
+            "batch": { "name": "IPA", "og": null },
+        });
+        let check = |source| {
+            Expression::parse(source)
+                .unwrap()
+                .check_variables(&variables)
+                .map_err(|e| e.to_string())
+        };
+        assert!(check("batch.og > gravity && batch.name == 'IPA'").is_ok());
+        assert_eq!(
+            check("gravity < 1.02 && colour == 'red'").unwrap_err(),
+            "Unknown variable colour at position 19 in \"gravity < 1.02 && colour == 'red'\""
+        );
+        assert_eq!(
+            check("batch.style == 'IPA'").unwrap_err(),
+            "Unknown variable batch.style at position 1 in \"batch.style == 'IPA'\""
+        );
+        assert_eq!(
+            check("color.name == 'red'").unwrap_err(),
+            "Unknown variable color.name at position 1 in \"color.name == 'red'\""
+        );
+        assert_eq!(
+            check("batch == null").unwrap_err(),
+            "batch is a table, so use one of its fields at position 1 in \"batch == null\""
+        );
+    }
+}
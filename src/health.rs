@@ -0,0 +1,217 @@
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// The `circuit-breaker` option of an emitter.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BreakerOptions {
+    #[serde(default = "default_failures")]
+    failures: usize,
+    #[serde(rename = "cool-down")]
+    #[serde(with = "humantime_serde")]
+    #[serde(default = "default_cool_down")]
+    cool_down: Duration,
+}
+
+impl Default for BreakerOptions {
+    fn default() -> BreakerOptions {
+        BreakerOptions {
+            failures: default_failures(),
+            cool_down: default_cool_down(),
+        }
+    }
+}
+
+impl BreakerOptions {
+    /// Without at least one failure, the breaker would never let anything
+    /// through.
+    pub fn validate(&self) -> Result<()> {
+        if self.failures == 0 {
+            bail!("failures must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+fn default_failures() -> usize {
+    5
+}
+fn default_cool_down() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// The `[health]` section.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HealthOptions {
+    pub file: PathBuf,
+}
+
+/// Whether an emitter is being sent anything.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum State {
+    /// It's working, or hasn't failed often enough to stop trying
+    #[default]
+    Closed,
+    /// It failed too many times in a row, so it isn't sent anything until
+    /// the cool-down is over
+    Open,
+    /// The cool-down is over, and the next attempt decides whether it's
+    /// working again
+    HalfOpen,
+    /// It failed in a way that won't fix itself
+    Disabled,
+}
+
+/// How an emitter is doing, for monitoring.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Health {
+    pub state: State,
+    pub consecutive_failures: usize,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_failure: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub queued: usize,
+    pub spooled: usize,
+}
+
+/// Stops sending to an emitter that keeps failing, so a service that's
+/// down doesn't cost a timeout and a warning for every reading. After a
+/// cool-down it tries again, and if that works everything goes back to
+/// normal.
+#[derive(Debug)]
+pub struct Breaker {
+    name: String,
+    options: BreakerOptions,
+    open_until: Instant,
+    pub health: Health,
+}
+
+impl Breaker {
+    pub fn new(name: &str, options: BreakerOptions) -> Breaker {
+        Breaker {
+            name: name.to_string(),
+            options,
+            open_until: Instant::now(),
+            health: Health::default(),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.health.state
+    }
+
+    /// Whether to try sending anything now.
+    pub fn allows(&mut self) -> bool {
+        match self.health.state {
+            State::Closed | State::HalfOpen => true,
+            State::Open if Instant::now() >= self.open_until => {
+                self.health.state = State::HalfOpen;
+                true
+            }
+            State::Open | State::Disabled => false,
+        }
+    }
+
+    /// How long until it's time to try again, if it's open.
+    pub fn remaining(&self) -> Option<Duration> {
+        match self.health.state {
+            State::Open => Some(self.open_until.saturating_duration_since(Instant::now())),
+            _ => None,
+        }
+    }
+
+    pub fn succeeded(&mut self) {
+        if self.health.state != State::Closed {
+            info!("{} is working again", self.name);
+            self.health.state = State::Closed;
+        }
+        self.health.consecutive_failures = 0;
+        self.health.last_success = Some(Utc::now());
+    }
+
+    /// A failure that might go away by itself.
+    pub fn failed(&mut self, error: &str) {
+        self.record(error);
+        let trip = match self.health.state {
+            State::Closed => self.health.consecutive_failures >= self.options.failures,
+            State::HalfOpen => true,
+            State::Open | State::Disabled => false,
+        };
+        if trip {
+            if self.health.state == State::Closed {
+                warn!(
+                    "{} failed {} times in a row, trying again every {}",
+                    self.name,
+                    self.health.consecutive_failures,
+                    humantime::format_duration(self.options.cool_down)
+                );
+            }
+            self.health.state = State::Open;
+            self.open_until = Instant::now() + self.options.cool_down;
+        }
+    }
+
+    /// A failure that won't go away until the config is fixed.
+    pub fn disable(&mut self, error: &str) {
+        self.record(error);
+        self.health.state = State::Disabled;
+    }
+
+    fn record(&mut self, error: &str) {
+        self.health.consecutive_failures += 1;
+        self.health.last_failure = Some(Utc::now());
+        self.health.last_error = Some(error.to_string());
+    }
+}
+
+/// Write the health of every emitter to a JSON file, by way of a temporary
+/// file so it's never half written.
+pub fn write(file: &Path, health: &BTreeMap<String, Health>) -> Result<()> {
+    let temporary = file.with_extension("tmp");
+    std::fs::write(&temporary, serde_json::to_string_pretty(health)?)?;
+    std::fs::rename(&temporary, file)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn breaker() {
+        let options: BreakerOptions = toml::from_str("failures = 3\ncool-down = \"20ms\"").unwrap();
+        let mut breaker = Breaker::new("test", options);
+        for _ in 0..3 {
+            assert!(breaker.allows());
+            breaker.failed("503");
+        }
+        assert_eq!(breaker.state(), State::Open);
+        assert!(!breaker.allows());
+
+        // A failed probe opens it again
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(breaker.allows());
+        assert_eq!(breaker.state(), State::HalfOpen);
+        breaker.failed("503");
+        assert_eq!(breaker.state(), State::Open);
+        assert!(!breaker.allows());
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(breaker.allows());
+        breaker.succeeded();
+        assert_eq!(breaker.state(), State::Closed);
+        assert_eq!(breaker.health.consecutive_failures, 0);
+        assert_eq!(breaker.health.last_error.as_deref(), Some("503"));
+
+        breaker.disable("401");
+        assert!(!breaker.allows());
+        assert_eq!(breaker.remaining(), None);
+    }
+}
@@ -1,18 +1,54 @@
+mod alert;
+mod batch;
 mod bluez;
 mod bt;
 mod bt_parsing;
+mod calibration;
+mod config_edit;
+mod devices;
+mod dispatcher;
 mod emitters;
 mod event;
+mod expression;
+mod health;
 mod ibeacon_parsing;
+mod processors;
+mod queue;
+mod shutdown;
+mod sources;
+mod spool;
+mod units;
+mod watchdog;
+mod wizard;
 
+use anyhow::Context;
 use anyhow::Result;
 use clap::Clap;
-use emitters::{Emitter, Emitters};
-use event::Dispatcher;
+use devices::{DeviceConfig, Devices};
+use dispatcher::{Dispatcher, Module};
+use emitters::EmitterSection;
+use health::HealthOptions;
+use processors::{
+    fermentation::FermentationOptions, metrics::MetricsOptions, outliers::OutlierOptions,
+    smooth::Smoothing, thresholds::AlertRule, Pipeline, Sections, Stages,
+};
 use serde::Deserialize;
+use shutdown::Shutdown;
+use sources::{Source, Sources};
 use std::collections::HashMap;
 use std::fs::read_to_string;
-use tracing::error;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+use watchdog::{OfflineOptions, Watchdog};
+
+/// How often to check for devices that have gone offline, to send the
+/// device status to the emitters, and to write the health file.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long the emitters get to send what's queued when tilted is
+/// stopping.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[macro_use]
 extern crate num_derive;
@@ -24,24 +60,92 @@ struct Opts {
     config: String,
     #[clap(short, long, parse(from_occurrences))]
     verbosity: i32,
+    #[clap(subcommand)]
+    subcommand: Option<SubCommand>,
+}
+
+#[derive(Clap, Debug)]
+enum SubCommand {
+    /// Work out the calibration of a tilt, and write it to the config file
+    Calibrate(wizard::CalibrateOpts),
+    /// Start or end a batch, and write it to the config file
+    Batch(batch::BatchOpts),
 }
 
 #[derive(Deserialize, Debug)]
 struct Config {
+    #[serde(default = "sources::default_sources")]
+    source: HashMap<String, Sources>,
+    #[serde(default)]
+    device: HashMap<String, DeviceConfig>,
+    #[serde(default)]
+    smoothing: HashMap<String, Smoothing>,
+    outliers: Option<OutlierOptions>,
+    #[serde(default)]
+    metrics: MetricsOptions,
+    fermentation: Option<FermentationOptions>,
+    #[serde(default)]
+    alert: Vec<AlertRule>,
+    offline: Option<OfflineOptions>,
+    health: Option<HealthOptions>,
+    #[serde(default)]
+    pipeline: Vec<Stages>,
     #[serde(flatten)]
-    emitters: HashMap<String, Emitters>,
+    emitters: HashMap<String, EmitterSection>,
+}
+
+struct Modules {
+    sources: Vec<Box<dyn Source>>,
+    pipeline: Pipeline,
+    watchdog: Watchdog,
+    emitters: Vec<Module>,
+    health: Option<HealthOptions>,
 }
 
-fn load(config_str: &str) -> Result<Vec<Box<dyn Emitter>>> {
+fn load(config_str: &str) -> Result<Modules> {
     let config: Config = toml::from_str(&config_str)?;
-    let modules = emitters::init(&config.emitters)?;
-    Ok(modules)
+    let sources = sources::init(&config.source)?;
+    let devices = Devices::new(&config.device)?;
+    for (name, smoothing) in &config.smoothing {
+        smoothing
+            .validate()
+            .with_context(|| format!("Invalid smoothing filter {}", name))?;
+    }
+    if let Some(outliers) = &config.outliers {
+        outliers.validate()?;
+    }
+    config.metrics.validate()?;
+    if let Some(fermentation) = &config.fermentation {
+        fermentation.validate()?;
+    }
+    for rule in &config.alert {
+        rule.validate()
+            .with_context(|| format!("Invalid alert rule {}", rule.name()))?;
+    }
+    let sections = Sections {
+        devices,
+        outliers: config.outliers,
+        smoothing: config.smoothing,
+        metrics: config.metrics,
+        fermentation: config.fermentation,
+        alerts: config.alert,
+    };
+    let pipeline = Pipeline::new(&config.pipeline, &sections)?;
+    let watchdog = Watchdog::new(config.offline.clone())?;
+    let emitters = emitters::init(&config.emitters, &sections)?;
+    Ok(Modules {
+        sources,
+        pipeline,
+        watchdog,
+        emitters,
+        health: config.health,
+    })
 }
 
 fn main() -> Result<()> {
     env_logger::init();
     let opts: Opts = Opts::parse();
-    let config_str = read_to_string(opts.config)?;
+    let config_str = read_to_string(&opts.config)?;
     let modules = load(&config_str);
     let modules = match modules {
         Err(e) => {
@@ -50,9 +154,59 @@ fn main() -> Result<()> {
         }
         Ok(modules) => modules,
     };
-    let dispatcher = Dispatcher { modules };
-    bt::run(&dispatcher)?;
-    Ok(())
+    match &opts.subcommand {
+        Some(SubCommand::Calibrate(calibrate)) => {
+            return wizard::run(calibrate, &opts.config, &config_str, modules.sources)
+        }
+        Some(SubCommand::Batch(batch)) => return batch::run(batch, &opts.config, &config_str),
+        None => {}
+    }
+    let dispatcher = Dispatcher::new(modules.emitters)?;
+
+    let shutdown = Shutdown::new();
+    shutdown.register_signals()?;
+    // The sources stop when asked to, and the loop stops when they have
+    let (receiver, handles) = sources::spawn(modules.sources, &shutdown);
+    let mut pipeline = modules.pipeline;
+    let mut watchdog = modules.watchdog;
+    let mut last_check = Instant::now();
+    loop {
+        match receiver.recv_timeout(CHECK_INTERVAL) {
+            Ok(event) => {
+                // Readings that are dropped still show that the device is
+                // alive
+                let raw = event.clone();
+                let event = pipeline.process(event);
+                let seen = event.as_ref().unwrap_or(&raw);
+                if let Some(alert) = watchdog.seen(seen, chrono::Utc::now()) {
+                    dispatcher.alert(&alert);
+                }
+                if let Some(event) = event {
+                    dispatcher.dispatch(&event);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if last_check.elapsed() >= CHECK_INTERVAL {
+            last_check = Instant::now();
+            for alert in watchdog.check(chrono::Utc::now()) {
+                dispatcher.alert(&alert);
+            }
+            dispatcher.status(&watchdog.status());
+            if let Some(health) = &modules.health {
+                if let Err(e) = health::write(&health.file, &dispatcher.health()) {
+                    warn!("Couldn't write {}: {}", health.file.display(), e);
+                }
+            }
+        }
+    }
+
+    if shutdown.requested() {
+        info!("Stopping");
+    }
+    dispatcher.close(SHUTDOWN_TIMEOUT);
+    sources::join(handles)
 }
 
 #[cfg(test)]
@@ -63,7 +217,9 @@ mod test {
     fn empty_config() {
         let modules = load(r#""#);
         assert!(modules.is_ok());
-        assert_eq!(modules.unwrap().len(), 0);
+        let modules = modules.unwrap();
+        assert_eq!(modules.emitters.len(), 0);
+        assert_eq!(modules.sources.len(), 1);
     }
 
     #[test]
@@ -95,7 +251,7 @@ temp_gauge_name = "temp_foo"
 gravity_gauge_name = "gravity_foo"
 "#,
         )?;
-        assert!(modules.len() == 3);
+        assert!(modules.emitters.len() == 3);
         Ok(())
     }
 
@@ -112,7 +268,389 @@ url = "http://bar"
 payload = {}
 "#,
         )?;
-        assert!(modules.len() == 2);
+        assert!(modules.emitters.len() == 2);
+        Ok(())
+    }
+
+    #[test]
+    fn timestamp_format_config() -> Result<(), Box<dyn std::error::Error>> {
+        load(
+            r#"[brewservice]
+emitter = "http"
+url = "http://foo"
+timestamp-format = "%Y-%m-%d %H:%M"
+payload = { time = "{ timestamp }" }
+"#,
+        )?;
+        let modules = load(
+            r#"[brewservice]
+emitter = "http"
+url = "http://foo"
+timestamp-format = "%Q"
+payload = {}
+"#,
+        );
+        assert!(modules.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn units_config() -> Result<(), Box<dyn std::error::Error>> {
+        let modules = load(
+            r#"[brewservice]
+emitter = "http"
+url = "http://foo"
+temperature-unit = "celsius"
+gravity-unit = "plato"
+payload = {}
+
+[prometheus]
+emitter = "prometheus"
+address = "foo"
+temp_gauge_name = "temp_foo"
+gravity_gauge_name = "gravity_foo"
+temperature_unit = "celsius"
+gravity_unit = "brix"
+"#,
+        )?;
+        assert!(modules.emitters.len() == 2);
+        Ok(())
+    }
+
+    #[test]
+    fn device_config() -> Result<(), Box<dyn std::error::Error>> {
+        load(
+            r#"[device.red.calibration]
+gravity = { offset = -0.002 }
+temperature = { points = [[32, 33], [212, 211]] }
+
+[device."AA:BB:CC:DD:EE:FF".calibration]
+gravity = { polynomial = [0.0012, 0.9986] }
+
+[device.blue]
+temperature-correction = { reference = 20, unit = "celsius" }
+
+[device.blue.batch]
+name = "IPA #42"
+style = "IPA"
+start = 2021-03-01T12:00:00Z
+og = 1.062
+target-fg = 1.012
+"#,
+        )?;
+        let modules = load(
+            r#"[device.red.calibration]
+gravity = { points = [] }
+"#,
+        );
+        assert!(modules.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn smoothing_config() -> Result<(), Box<dyn std::error::Error>> {
+        let modules = load(
+            r#"[smoothing.average]
+filter = "moving-average"
+window = 10
+
+[smoothing.median]
+filter = "median"
+window = 5
+
+[smoothing.ewma]
+filter = "exponential"
+alpha = 0.2
+
+[smoothing.kalman]
+filter = "kalman"
+gravity-measurement-noise = 0.002
+
+[log]
+emitter = "log"
+smoothing = "kalman"
+"#,
+        )?;
+        assert!(modules.emitters.len() == 1);
+        let modules = load(
+            r#"[log]
+emitter = "log"
+smoothing = "missing"
+"#,
+        );
+        assert!(modules.is_err());
+        let modules = load(
+            r#"[smoothing.ewma]
+filter = "exponential"
+alpha = 2
+"#,
+        );
+        assert!(modules.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn outliers_config() -> Result<(), Box<dyn std::error::Error>> {
+        load(
+            r#"[outliers]
+action = "tag"
+max-gravity-rate = 0.03
+mad-window = 20
+"#,
+        )?;
+        assert!(load("[outliers]\naction = \"drop\"\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn metrics_config() -> Result<(), Box<dyn std::error::Error>> {
+        load(
+            r#"[metrics]
+rate-window = "12h"
+og-readings = 20
+og-tolerance = 0.001
+
+[device.red]
+og = 1.062
+"#,
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn fermentation_config() -> Result<(), Box<dyn std::error::Error>> {
+        load(
+            r#"[fermentation]
+tolerance = 0.001
+period = "2d"
+fg-tolerance = 0.004
+stuck-margin = 0.008
+
+[webhook]
+emitter = "http"
+url = "http://foo"
+payload = {}
+alert-payload = { text = "{ alert.message }" }
+"#,
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn alert_config() -> Result<(), Box<dyn std::error::Error>> {
+        let modules = load(
+            r#"[[alert]]
+name = "Fridge failed"
+condition = "temperature-above"
+threshold = 24
+hysteresis = 1
+duration = "30m"
+temperature-unit = "celsius"
+
+[[alert]]
+name = "Slow fermentation"
+condition = "gravity-rate-above"
+threshold = -0.002
+devices = ["red", "AA:BB:CC:DD:EE:FF"]
+
+[pager]
+emitter = "http"
+url = "http://foo"
+events = "alerts"
+alert-payload = { text = "{ alert.message }" }
+"#,
+        )?;
+        assert!(modules.emitters.len() == 1);
+        let modules = load(
+            r#"[[alert]]
+name = "Cold"
+condition = "temperature-colder"
+threshold = 50
+"#,
+        );
+        assert!(modules.is_err());
+        let modules = load(
+            r#"[http]
+emitter = "http"
+url = "http://foo"
+"#,
+        );
+        assert!(modules.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn offline_config() -> Result<(), Box<dyn std::error::Error>> {
+        load(
+            r#"[offline]
+after = "1h"
+
+[prometheus]
+emitter = "prometheus"
+address = "foo"
+temp_gauge_name = "temp_foo"
+gravity_gauge_name = "gravity_foo"
+last_seen_gauge_name = "last_seen_foo"
+"#,
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn health_config() -> Result<(), Box<dyn std::error::Error>> {
+        load(
+            r#"[health]
+file = "/run/tilted/health.json"
+
+[brewfather]
+emitter = "http"
+url = "http://foo"
+payload = {}
+circuit-breaker = { failures = 3, cool-down = "5m" }
+"#,
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn pipeline_config() -> Result<(), Box<dyn std::error::Error>> {
+        load(
+            r#"[[pipeline]]
+stage = "identify"
+
+[[pipeline]]
+stage = "validate"
+max-temperature = 40
+temperature-unit = "celsius"
+
+[[pipeline]]
+stage = "calibrate"
+
+[[pipeline]]
+stage = "filter"
+exclude = { names = ["Spare"] }
+
+[[pipeline]]
+stage = "rename"
+devices = { colors = ["red"] }
+name = "Fermenter"
+
+[brewfather]
+emitter = "http"
+url = "http://foo"
+payload = {}
+pipeline = [{ stage = "smooth" }, { stage = "fermentation" }]
+"#,
+        )?;
+        assert!(load("[[pipeline]]\nstage = \"outliers\"\n").is_err());
+        assert!(load("[[pipeline]]\nstage = \"convert\"\n").is_err());
+        assert!(load("[[pipeline]]\nstage = \"identify\"\nname = \"foo\"\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn expression_config() -> Result<(), Box<dyn std::error::Error>> {
+        load(
+            r#"[brewfather]
+emitter = "http"
+url = "http://foo"
+payload = { plato = "{ plato }", done = "{ done }" }
+when = "gravity < 1.020 && color == 'red'"
+fields = { plato = "259 - 259 / gravity", done = "if(gravity < 1.012, 'yes', 'no')" }
+"#,
+        )?;
+        let error = |config| format!("{:#}", load(config).err().unwrap());
+        assert!(error("[log]\nemitter = \"log\"\nwhen = \"gravity <\"\n")
+            .contains("Expected a value at position 10 in \"gravity <\""));
+        assert!(
+            error("[log]\nemitter = \"log\"\nwhen = \"colour == 'red'\"\n")
+                .contains("Unknown variable colour at position 1")
+        );
+        assert!(
+            error("[log]\nemitter = \"log\"\nfields = { \"a b\" = \"1\" }\n")
+                .contains("can't be the name of a field")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn queue_config() -> Result<(), Box<dyn std::error::Error>> {
+        load(
+            r#"[brewfather]
+emitter = "http"
+url = "http://foo"
+payload = {}
+timeout = "10s"
+queue-size = 10
+overflow = "drop-newest"
+
+[prometheus]
+emitter = "prometheus"
+address = "foo"
+temp_gauge_name = "temp_foo"
+gravity_gauge_name = "gravity_foo"
+timeout = "5s"
+overflow = "block"
+"#,
+        )?;
+        assert!(load("[log]\nemitter = \"log\"\nqueue-size = 0\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_emitter_option() {
+        let modules = load(
+            r#"[log]
+emitter = "log"
+smothing = "kalman"
+"#,
+        );
+        assert!(modules.is_err());
+    }
+
+    #[test]
+    fn source_config() -> Result<(), Box<dyn std::error::Error>> {
+        let modules = load(
+            r#"[source.scanner]
+source = "bluetooth"
+
+[log]
+emitter = "log"
+"#,
+        )?;
+        assert!(modules.sources.len() == 1);
+        assert!(modules.emitters.len() == 1);
+        Ok(())
+    }
+
+    #[test]
+    fn simulator_config() -> Result<(), Box<dyn std::error::Error>> {
+        let modules = load(
+            r#"[source.demo]
+source = "simulator"
+colors = ["red", "blue"]
+og = 1.060
+fg = 1.012
+lag = "6h"
+duration = "5d"
+temperature-profile = [{ at = "3d", temperature = 72 }]
+speedup = 1000
+"#,
+        )?;
+        assert!(modules.sources.len() == 1);
+        Ok(())
+    }
+
+    #[test]
+    fn no_sources() -> Result<(), Box<dyn std::error::Error>> {
+        let modules = load(
+            r#"[source]
+
+[log]
+emitter = "log"
+"#,
+        )?;
+        assert!(modules.sources.is_empty());
         Ok(())
     }
 }
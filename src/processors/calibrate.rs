@@ -0,0 +1,32 @@
+use super::Processor;
+use crate::devices::Devices;
+use crate::event::Event;
+use crate::units::round;
+
+/// Applies the per-device calibration from the config. The uncalibrated
+/// values stay available in `Event::raw`. Results are rounded to a bit
+/// more precision than the device has, to get rid of floating point noise.
+#[derive(Debug)]
+pub struct Calibrate {
+    devices: Devices,
+}
+
+impl Calibrate {
+    pub fn new(devices: Devices) -> Calibrate {
+        Calibrate { devices }
+    }
+}
+
+impl Processor for Calibrate {
+    fn process(&mut self, mut event: Event) -> Option<Event> {
+        if let Some(device) = self.devices.get(&event) {
+            if let Some(calibration) = &device.calibration.temperature {
+                event.temperature = round(calibration.apply(event.temperature), 2);
+            }
+            if let Some(calibration) = &device.calibration.gravity {
+                event.gravity = round(calibration.apply(event.gravity), 4);
+            }
+        }
+        Some(event)
+    }
+}
@@ -0,0 +1,45 @@
+use super::{Processor, ProcessorConfig, Sections};
+use crate::event::Event;
+use crate::units::{GravityUnit, TemperatureUnit};
+use anyhow::{bail, Result};
+use serde::Deserialize;
+
+/// The options of the `convert` stage.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ConvertOptions {
+    #[serde(rename = "temperature-unit")]
+    temperature_unit: Option<TemperatureUnit>,
+    #[serde(rename = "gravity-unit")]
+    gravity_unit: Option<GravityUnit>,
+}
+
+impl ProcessorConfig for ConvertOptions {
+    fn get_processor(&self, _sections: &Sections) -> Result<Box<dyn Processor>> {
+        if self.temperature_unit.is_none() && self.gravity_unit.is_none() {
+            bail!("The convert stage needs a temperature-unit or a gravity-unit");
+        }
+        Ok(Box::new(Convert {
+            options: self.clone(),
+        }))
+    }
+}
+
+/// Sets the units the emitters send readings in. The readings themselves
+/// stay in Fahrenheit and SG, so the stages after this one work the same.
+#[derive(Debug)]
+pub struct Convert {
+    options: ConvertOptions,
+}
+
+impl Processor for Convert {
+    fn process(&mut self, mut event: Event) -> Option<Event> {
+        if let Some(unit) = self.options.temperature_unit {
+            event.temperature_unit = Some(unit);
+        }
+        if let Some(unit) = self.options.gravity_unit {
+            event.gravity_unit = Some(unit);
+        }
+        Some(event)
+    }
+}
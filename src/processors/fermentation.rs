@@ -0,0 +1,299 @@
+use super::{median, Processor};
+use crate::alert::{Alert, AlertKind};
+use crate::event::Event;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use tracing::info;
+
+/// The `[fermentation]` section.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FermentationOptions {
+    #[serde(default = "default_tolerance")]
+    tolerance: f64,
+    #[serde(with = "humantime_serde")]
+    #[serde(default = "default_period")]
+    period: Duration,
+    #[serde(rename = "fg-tolerance")]
+    fg_tolerance: Option<f64>,
+    #[serde(rename = "stuck-margin")]
+    #[serde(default = "default_stuck_margin")]
+    stuck_margin: f64,
+    #[serde(rename = "min-drop")]
+    #[serde(default = "default_min_drop")]
+    min_drop: f64,
+}
+
+impl Default for FermentationOptions {
+    fn default() -> FermentationOptions {
+        FermentationOptions {
+            tolerance: default_tolerance(),
+            period: default_period(),
+            fg_tolerance: None,
+            stuck_margin: default_stuck_margin(),
+            min_drop: default_min_drop(),
+        }
+    }
+}
+
+fn default_tolerance() -> f64 {
+    0.002
+}
+fn default_period() -> Duration {
+    Duration::from_secs(3 * 24 * 60 * 60)
+}
+fn default_stuck_margin() -> f64 {
+    0.01
+}
+fn default_min_drop() -> f64 {
+    0.005
+}
+
+impl FermentationOptions {
+    pub fn validate(&self) -> Result<()> {
+        if self.tolerance < 0. || self.stuck_margin < 0. || self.min_drop < 0. {
+            bail!("tolerance, stuck-margin and min-drop can't be negative");
+        }
+        if self.fg_tolerance.is_some_and(|tolerance| tolerance < 0.) {
+            bail!("fg-tolerance can't be negative");
+        }
+        if self.period.as_secs() == 0 {
+            bail!("period must be longer than 0");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct DeviceState {
+    batch: Option<String>,
+    history: VecDeque<(DateTime<Utc>, f64)>,
+    reported: Option<AlertKind>,
+}
+
+/// Decides when the gravity of a device has stopped changing, and alerts
+/// that the fermentation is complete - or stuck, if the gravity stopped
+/// well above the target final gravity of the batch. Nothing is reported
+/// until the gravity has dropped from the original gravity, so the lag
+/// phase after pitching doesn't count.
+#[derive(Debug)]
+pub struct Fermentation {
+    options: FermentationOptions,
+    period: chrono::Duration,
+    state: HashMap<String, DeviceState>,
+}
+
+impl Fermentation {
+    pub fn new(options: FermentationOptions) -> Result<Fermentation> {
+        Ok(Fermentation {
+            period: chrono::Duration::from_std(options.period).context("period is too long")?,
+            options,
+            state: HashMap::new(),
+        })
+    }
+}
+
+impl Processor for Fermentation {
+    fn process(&mut self, mut event: Event) -> Option<Event> {
+        let state = self.state.entry(event.device()).or_default();
+        let batch = event.batch.as_ref().map(|batch| batch.name.clone());
+        if state.batch != batch {
+            *state = DeviceState {
+                batch,
+                ..DeviceState::default()
+            };
+        }
+
+        let period = self.period;
+        state.history.push_back((event.timestamp, event.gravity));
+        while state.history.len() > 1 && event.timestamp - state.history[1].0 >= period {
+            state.history.pop_front();
+        }
+        let gravity = match stable_gravity(&state.history, period, self.options.tolerance) {
+            Some(gravity) => gravity,
+            None => {
+                state.reported = None;
+                return Some(event);
+            }
+        };
+        // A gravity that hasn't dropped from the original gravity hasn't
+        // started fermenting, or the tilt isn't in beer
+        let min_drop = self.options.min_drop;
+        let started = event.og.is_some_and(|og| og - gravity > min_drop);
+        if !started {
+            return Some(event);
+        }
+
+        let target = event.batch.as_ref().and_then(|batch| batch.target_fg);
+        let kind = match target {
+            Some(target) if gravity - target > self.options.stuck_margin => {
+                Some(AlertKind::StuckFermentation)
+            }
+            Some(target)
+                if self
+                    .options
+                    .fg_tolerance
+                    .is_some_and(|tolerance| (gravity - target).abs() > tolerance) =>
+            {
+                None
+            }
+            _ => Some(AlertKind::FermentationComplete),
+        };
+        if kind.is_none() || kind == state.reported {
+            return Some(event);
+        }
+        state.reported = kind;
+
+        let beer = match &event.batch {
+            Some(batch) => format!("{} on the {} tilt", batch.name, event.device()),
+            None => format!("the {} tilt", event.device()),
+        };
+        let alert = match (kind, target) {
+            (Some(AlertKind::StuckFermentation), Some(target)) => Alert::new(
+                AlertKind::StuckFermentation,
+                format!(
+                    "Fermentation of {} has stalled at {:.3}, above the target of {:.3}",
+                    beer, gravity, target
+                ),
+                &event,
+            ),
+            _ => Alert::new(
+                AlertKind::FermentationComplete,
+                format!("Fermentation of {} is complete at {:.3}", beer, gravity),
+                &event,
+            ),
+        };
+        info!("{}", alert.message);
+        event.alerts.push(alert);
+        Some(event)
+    }
+}
+
+/// The current gravity, if the readings cover the whole period and the
+/// gravity changed by no more than `tolerance` during it. To not be
+/// thrown by single noisy readings, it compares the median of the first
+/// tenth of the period to the median of the last tenth.
+fn stable_gravity(
+    history: &VecDeque<(DateTime<Utc>, f64)>,
+    period: chrono::Duration,
+    tolerance: f64,
+) -> Option<f64> {
+    let (first, _) = *history.front()?;
+    let (last, _) = *history.back()?;
+    if last - first < period {
+        return None;
+    }
+    let slice = period / 10;
+    let start = history
+        .iter()
+        .take_while(|(timestamp, _)| *timestamp - first <= slice)
+        .map(|(_, gravity)| *gravity)
+        .collect::<Vec<_>>();
+    let end = history
+        .iter()
+        .filter(|(timestamp, _)| last - *timestamp <= slice)
+        .map(|(_, gravity)| *gravity)
+        .collect::<Vec<_>>();
+    let (start, end) = (median(&start), median(&end));
+    if (start - end).abs() > tolerance + 1e-9 {
+        return None;
+    }
+    Some(end)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::batch::Batch;
+    use crate::event::Color;
+
+    /// Four days of hourly readings, dropping from 1.050 to `fg` over the
+    /// first day.
+    fn ferment(fermentation: &mut Fermentation, fg: f64, batch: Option<Batch>) -> Vec<Alert> {
+        let start = Utc::now();
+        let mut alerts = vec![];
+        for hour in 0..96 {
+            let gravity = 1.050 - (1.050 - fg) * (hour as f64 / 24.).min(1.);
+            let mut event = Event::new(
+                Color::Red,
+                None,
+                start + chrono::Duration::hours(hour),
+                68.,
+                gravity,
+            );
+            event.batch = batch.clone();
+            event.og = Some(1.050);
+            alerts.extend(fermentation.process(event).unwrap().alerts);
+        }
+        alerts
+    }
+
+    fn options() -> FermentationOptions {
+        FermentationOptions {
+            tolerance: 0.002,
+            period: Duration::from_secs(48 * 60 * 60),
+            fg_tolerance: Some(0.004),
+            stuck_margin: 0.01,
+            min_drop: 0.005,
+        }
+    }
+
+    #[test]
+    fn complete() {
+        let alerts = ferment(&mut Fermentation::new(options()).unwrap(), 1.010, None);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, AlertKind::FermentationComplete);
+        assert_eq!(alerts[0].event.gravity, 1.010);
+    }
+
+    #[test]
+    fn stuck() {
+        let batch = Batch {
+            name: "IPA #42".to_string(),
+            style: None,
+            start: None,
+            end: None,
+            og: None,
+            target_fg: Some(1.010),
+        };
+        let alerts = ferment(
+            &mut Fermentation::new(options()).unwrap(),
+            1.030,
+            Some(batch.clone()),
+        );
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, AlertKind::StuckFermentation);
+        // Between the stuck margin and the FG tolerance, it's neither
+        let alerts = ferment(
+            &mut Fermentation::new(options()).unwrap(),
+            1.017,
+            Some(batch.clone()),
+        );
+        assert!(alerts.is_empty());
+        let alerts = ferment(
+            &mut Fermentation::new(options()).unwrap(),
+            1.012,
+            Some(batch),
+        );
+        assert_eq!(alerts[0].kind, AlertKind::FermentationComplete);
+    }
+
+    #[test]
+    fn not_started() {
+        // A flat gravity at the original gravity, like the lag phase or a
+        // tilt in water
+        let alerts = ferment(&mut Fermentation::new(options()).unwrap(), 1.050, None);
+        assert!(alerts.is_empty());
+        let alerts = ferment(&mut Fermentation::new(options()).unwrap(), 1.047, None);
+        assert!(alerts.is_empty());
+
+        let options = FermentationOptions {
+            period: Duration::from_secs(u64::MAX),
+            ..options()
+        };
+        assert!(Fermentation::new(options).is_err());
+    }
+}
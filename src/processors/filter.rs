@@ -0,0 +1,44 @@
+use super::{Processor, ProcessorConfig, Sections};
+use crate::dispatcher::DeviceFilter;
+use crate::event::Event;
+use anyhow::Result;
+use serde::Deserialize;
+
+/// The options of the `filter` stage.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FilterOptions {
+    include: Option<DeviceFilter>,
+    exclude: Option<DeviceFilter>,
+}
+
+impl ProcessorConfig for FilterOptions {
+    fn get_processor(&self, _sections: &Sections) -> Result<Box<dyn Processor>> {
+        Ok(Box::new(Filter {
+            options: self.clone(),
+        }))
+    }
+}
+
+/// Drops readings from devices that don't match `include`, or that match
+/// `exclude`.
+#[derive(Debug)]
+pub struct Filter {
+    options: FilterOptions,
+}
+
+impl Processor for Filter {
+    fn process(&mut self, event: Event) -> Option<Event> {
+        let included = self
+            .options
+            .include
+            .as_ref()
+            .is_none_or(|include| include.matches(&event));
+        let excluded = self
+            .options
+            .exclude
+            .as_ref()
+            .is_some_and(|exclude| exclude.matches(&event));
+        Some(event).filter(|_| included && !excluded)
+    }
+}
@@ -0,0 +1,32 @@
+use super::Processor;
+use crate::devices::Devices;
+use crate::event::Event;
+
+/// Tags events with the name of the device, and the batch that was
+/// active in the device when the reading was taken.
+#[derive(Debug)]
+pub struct Identify {
+    devices: Devices,
+}
+
+impl Identify {
+    pub fn new(devices: Devices) -> Identify {
+        Identify { devices }
+    }
+}
+
+impl Processor for Identify {
+    fn process(&mut self, mut event: Event) -> Option<Event> {
+        let device = match self.devices.get(&event) {
+            Some(device) => device,
+            None => return Some(event),
+        };
+        event.name = device.name.clone();
+        event.batch = device
+            .batch
+            .as_ref()
+            .filter(|batch| batch.is_active(&event.timestamp))
+            .cloned();
+        Some(event)
+    }
+}
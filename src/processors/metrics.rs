@@ -0,0 +1,232 @@
+use super::Processor;
+use crate::devices::Devices;
+use crate::event::Event;
+use crate::units::round;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use tracing::info;
+
+/// The `[metrics]` section.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsOptions {
+    #[serde(rename = "rate-window")]
+    #[serde(with = "humantime_serde")]
+    #[serde(default = "default_rate_window")]
+    rate_window: Duration,
+    #[serde(rename = "og-readings")]
+    #[serde(default = "default_og_readings")]
+    og_readings: usize,
+    #[serde(rename = "og-tolerance")]
+    #[serde(default = "default_og_tolerance")]
+    og_tolerance: f64,
+}
+
+fn default_rate_window() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+fn default_og_readings() -> usize {
+    10
+}
+fn default_og_tolerance() -> f64 {
+    0.002
+}
+
+impl Default for MetricsOptions {
+    fn default() -> MetricsOptions {
+        MetricsOptions {
+            rate_window: default_rate_window(),
+            og_readings: default_og_readings(),
+            og_tolerance: default_og_tolerance(),
+        }
+    }
+}
+
+impl MetricsOptions {
+    pub fn validate(&self) -> Result<()> {
+        if self.og_readings == 0 {
+            bail!("og-readings must be at least 1");
+        }
+        if self.rate_window.as_secs() == 0 {
+            bail!("rate-window must be longer than 0");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct DeviceState {
+    batch: Option<String>,
+    og: Option<f64>,
+    history: VecDeque<(DateTime<Utc>, f64)>,
+}
+
+/// Adds the original gravity, ABV, apparent attenuation and the rate the
+/// gravity changes at to every event. The original gravity comes from the
+/// device's config, or else it's the first stable gravity the device
+/// reports. A new batch starts over with a new original gravity.
+#[derive(Debug)]
+pub struct Metrics {
+    options: MetricsOptions,
+    window: chrono::Duration,
+    devices: Devices,
+    state: HashMap<String, DeviceState>,
+}
+
+impl Metrics {
+    pub fn new(options: MetricsOptions, devices: Devices) -> Result<Metrics> {
+        Ok(Metrics {
+            window: chrono::Duration::from_std(options.rate_window)
+                .context("rate-window is too long")?,
+            options,
+            devices,
+            state: HashMap::new(),
+        })
+    }
+}
+
+/// Work out the ABV and apparent attenuation from the original gravity
+/// and the gravity of an event.
+pub fn og_metrics(event: &mut Event) {
+    if let Some(og) = event.og {
+        event.abv = Some(round((og - event.gravity) * 131.25, 2));
+        if og > 1. {
+            event.attenuation = Some(round((og - event.gravity) / (og - 1.) * 100., 1));
+        }
+    }
+}
+
+impl Processor for Metrics {
+    fn process(&mut self, mut event: Event) -> Option<Event> {
+        let configured_og = event
+            .batch
+            .as_ref()
+            .and_then(|batch| batch.og)
+            .or_else(|| self.devices.get(&event).and_then(|device| device.og));
+        let state = self.state.entry(event.device()).or_default();
+        let batch = event.batch.as_ref().map(|batch| batch.name.clone());
+        if state.batch != batch {
+            *state = DeviceState {
+                batch,
+                ..DeviceState::default()
+            };
+        }
+
+        let window = self.window;
+        state.history.push_back((event.timestamp, event.gravity));
+        while let Some((timestamp, _)) = state.history.front() {
+            if event.timestamp - *timestamp <= window {
+                break;
+            }
+            state.history.pop_front();
+        }
+
+        if configured_og.is_none() && state.og.is_none() {
+            state.og = stable_gravity(
+                &state.history,
+                self.options.og_readings,
+                self.options.og_tolerance,
+            );
+            if let Some(og) = state.og {
+                info!("Detected original gravity {} for {}", og, event.device());
+            }
+        }
+        event.og = configured_og.or(state.og);
+        og_metrics(&mut event);
+        // Don't guess at a rate from too short a stretch of readings
+        let span = state
+            .history
+            .front()
+            .map(|(first, _)| event.timestamp - *first)
+            .unwrap_or_else(chrono::Duration::zero);
+        if span * 4 >= window {
+            event.gravity_rate = slope(&state.history).map(|rate| round(rate, 4));
+        }
+        Some(event)
+    }
+}
+
+/// The average of the last `readings` gravities, if they're all within
+/// `tolerance` of each other.
+fn stable_gravity(
+    history: &VecDeque<(DateTime<Utc>, f64)>,
+    readings: usize,
+    tolerance: f64,
+) -> Option<f64> {
+    if history.len() < readings {
+        return None;
+    }
+    let last = history
+        .iter()
+        .skip(history.len() - readings)
+        .map(|(_, g)| *g);
+    let min = last.clone().fold(f64::INFINITY, f64::min);
+    let max = last.clone().fold(f64::NEG_INFINITY, f64::max);
+    if max - min > tolerance + 1e-9 {
+        return None;
+    }
+    Some(round(last.sum::<f64>() / readings as f64, 4))
+}
+
+/// The least squares slope of the gravity, per day.
+fn slope(history: &VecDeque<(DateTime<Utc>, f64)>) -> Option<f64> {
+    let (start, _) = history.front()?;
+    let points = history
+        .iter()
+        .map(|(timestamp, gravity)| {
+            let days = (*timestamp - *start).num_milliseconds() as f64 / 86_400_000.;
+            (days, *gravity)
+        })
+        .collect::<Vec<_>>();
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let covariance = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum::<f64>();
+    let variance = points
+        .iter()
+        .map(|(x, _)| (x - mean_x).powi(2))
+        .sum::<f64>();
+    if variance == 0. {
+        return None;
+    }
+    Some(covariance / variance)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::event::Color;
+
+    #[test]
+    fn metrics() {
+        let options = MetricsOptions {
+            rate_window: Duration::from_secs(4 * 60 * 60),
+            og_readings: 3,
+            og_tolerance: 0.001,
+        };
+        let mut metrics = Metrics::new(options, Devices::default()).unwrap();
+        let start = Utc::now();
+        let mut last = None;
+        // Two points per hour, for a day
+        for i in 0..48 {
+            let timestamp = start + chrono::Duration::minutes(30 * i);
+            let gravity = 1.050 - 0.012 * i as f64 / 48.;
+            let event = Event::new(Color::Red, None, timestamp, 68., gravity);
+            last = metrics.process(event);
+        }
+        let last = last.unwrap();
+        assert_eq!(last.og, Some(1.0498));
+        assert!((last.abv.unwrap() - 1.52).abs() < 0.01);
+        assert!((last.attenuation.unwrap() - 23.2).abs() < 0.1);
+        assert!((last.gravity_rate.unwrap() + 0.012).abs() < 0.0001);
+
+        let options: MetricsOptions = toml::from_str("rate-window = \"1000000000y\"").unwrap();
+        assert!(Metrics::new(options, Devices::default()).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+pub mod calibrate;
+pub mod convert;
+pub mod fermentation;
+pub mod filter;
+pub mod identify;
+pub mod metrics;
+pub mod outliers;
+pub mod rename;
+pub mod smooth;
+pub mod temperature_correction;
+pub mod thresholds;
+pub mod validate;
+
+use crate::devices::Devices;
+use crate::event::Event;
+use anyhow::{bail, Context, Result};
+use fermentation::FermentationOptions;
+use metrics::MetricsOptions;
+use outliers::OutlierOptions;
+use serde::Deserialize;
+use smooth::Smoothing;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use thresholds::AlertRule;
+
+pub trait Processor: Debug + Send {
+    /// Transform an event on its way to the emitters, or return `None` to
+    /// drop it.
+    fn process(&mut self, event: Event) -> Option<Event>;
+}
+
+/// The parts of the config that the stages are built from.
+#[derive(Debug, Clone, Default)]
+pub struct Sections {
+    pub devices: Devices,
+    pub outliers: Option<OutlierOptions>,
+    pub smoothing: HashMap<String, Smoothing>,
+    pub metrics: MetricsOptions,
+    pub fermentation: Option<FermentationOptions>,
+    pub alerts: Vec<AlertRule>,
+}
+
+trait ProcessorConfig {
+    fn get_processor(&self, sections: &Sections) -> Result<Box<dyn Processor>>;
+}
+
+/// A `[[pipeline]]` entry, or an entry in the `pipeline` option of an
+/// emitter. The stages without options are empty structs rather than unit
+/// variants, since serde would let unit variants have any options at all.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "stage", rename_all = "kebab-case")]
+pub enum Stages {
+    Identify {},
+    Validate(validate::ValidateOptions),
+    Outliers {},
+    Calibrate {},
+    TemperatureCorrection {},
+    Smooth {},
+    Metrics {},
+    Fermentation {},
+    Alerts {},
+    Filter(filter::FilterOptions),
+    Rename(rename::RenameOptions),
+    Convert(convert::ConvertOptions),
+}
+
+impl ProcessorConfig for Stages {
+    fn get_processor(&self, sections: &Sections) -> Result<Box<dyn Processor>> {
+        Ok(match self {
+            Stages::Identify {} => Box::new(identify::Identify::new(sections.devices.clone())),
+            Stages::Validate(options) => return options.get_processor(sections),
+            Stages::Outliers {} => match &sections.outliers {
+                Some(outliers) => Box::new(outliers::Outliers::new(outliers.clone())),
+                None => bail!("The outliers stage needs an [outliers] section"),
+            },
+            Stages::Calibrate {} => Box::new(calibrate::Calibrate::new(sections.devices.clone())),
+            Stages::TemperatureCorrection {} => Box::new(temperature_correction::Correct::new(
+                sections.devices.clone(),
+            )),
+            Stages::Smooth {} => Box::new(smooth::Smooth::new(sections.smoothing.clone())),
+            Stages::Metrics {} => Box::new(metrics::Metrics::new(
+                sections.metrics.clone(),
+                sections.devices.clone(),
+            )?),
+            Stages::Fermentation {} => Box::new(fermentation::Fermentation::new(
+                sections.fermentation.clone().unwrap_or_default(),
+            )?),
+            Stages::Alerts {} => Box::new(thresholds::Thresholds::new(sections.alerts.clone())?),
+            Stages::Filter(options) => return options.get_processor(sections),
+            Stages::Rename(options) => return options.get_processor(sections),
+            Stages::Convert(options) => return options.get_processor(sections),
+        })
+    }
+}
+
+/// The stages used when the config doesn't list any.
+fn default_stages(sections: &Sections) -> Vec<Stages> {
+    let mut stages = vec![Stages::Identify {}];
+    if sections.outliers.is_some() {
+        stages.push(Stages::Outliers {});
+    }
+    stages.extend(vec![
+        Stages::Calibrate {},
+        Stages::TemperatureCorrection {},
+        Stages::Smooth {},
+        Stages::Metrics {},
+    ]);
+    if sections.fermentation.is_some() {
+        stages.push(Stages::Fermentation {});
+    }
+    if !sections.alerts.is_empty() {
+        stages.push(Stages::Alerts {});
+    }
+    stages
+}
+
+/// The stages every event goes through between the sources and the
+/// emitters, in order.
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    processors: Vec<Box<dyn Processor>>,
+}
+
+impl Pipeline {
+    /// The pipeline all events go through: the configured stages, or the
+    /// default ones if there aren't any.
+    pub fn new(stages: &[Stages], sections: &Sections) -> Result<Pipeline> {
+        if stages.is_empty() {
+            Pipeline::build(&default_stages(sections), sections)
+        } else {
+            Pipeline::build(stages, sections)
+        }
+    }
+
+    /// Exactly the given stages, which may be none.
+    pub fn build(stages: &[Stages], sections: &Sections) -> Result<Pipeline> {
+        let processors = stages
+            .iter()
+            .enumerate()
+            .map(|(i, stage)| {
+                stage
+                    .get_processor(sections)
+                    .with_context(|| format!("Invalid pipeline stage {}", i + 1))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Pipeline { processors })
+    }
+
+    pub fn process(&mut self, event: Event) -> Option<Event> {
+        self.processors
+            .iter_mut()
+            .try_fold(event, |event, processor| processor.process(event))
+    }
+}
+
+/// The median of some values, which mustn't be empty.
+pub fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let middle = sorted.len() / 2;
+    if sorted.len() % 2 == 1 {
+        sorted[middle]
+    } else {
+        (sorted[middle - 1] + sorted[middle]) / 2.
+    }
+}
@@ -0,0 +1,294 @@
+use super::{median, Processor};
+use crate::event::{Event, Reading};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use tracing::debug;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Drop,
+    Tag,
+}
+
+/// The `[outliers]` section.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct OutlierOptions {
+    #[serde(default)]
+    action: Action,
+    #[serde(default = "default_confirm")]
+    confirm: usize,
+    #[serde(rename = "max-gravity-rate")]
+    max_gravity_rate: Option<f64>, // Per day
+    #[serde(rename = "max-temperature-rate")]
+    max_temperature_rate: Option<f64>, // Per hour
+    #[serde(rename = "gravity-noise")]
+    #[serde(default = "default_gravity_noise")]
+    gravity_noise: f64,
+    #[serde(rename = "temperature-noise")]
+    #[serde(default = "default_temperature_noise")]
+    temperature_noise: f64,
+    #[serde(rename = "mad-window")]
+    mad_window: Option<usize>,
+    #[serde(rename = "mad-threshold")]
+    #[serde(default = "default_mad_threshold")]
+    mad_threshold: f64,
+}
+
+fn default_confirm() -> usize {
+    3
+}
+fn default_gravity_noise() -> f64 {
+    0.002
+}
+fn default_temperature_noise() -> f64 {
+    1.
+}
+fn default_mad_threshold() -> f64 {
+    3.5
+}
+
+impl OutlierOptions {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_gravity_rate.is_none()
+            && self.max_temperature_rate.is_none()
+            && self.mad_window.is_none()
+        {
+            bail!("outlier detection needs a max-gravity-rate, max-temperature-rate or mad-window");
+        }
+        if self.mad_window == Some(0) {
+            bail!("mad-window must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct DeviceState {
+    last: Option<(DateTime<Utc>, Reading)>,
+    window: VecDeque<Reading>,
+    held: Vec<Reading>,
+    rejected: u64,
+}
+
+/// Rejects readings that are implausibly far from the ones before them,
+/// either by changing faster than the configured rates, or by being far
+/// from the median of the last readings, measured in median absolute
+/// deviations.
+#[derive(Debug)]
+pub struct Outliers {
+    options: OutlierOptions,
+    devices: HashMap<String, DeviceState>,
+}
+
+impl Outliers {
+    pub fn new(options: OutlierOptions) -> Outliers {
+        Outliers {
+            options,
+            devices: HashMap::new(),
+        }
+    }
+
+    /// Why a reading is an outlier, or `None` if it isn't.
+    fn check(&self, state: &DeviceState, event: &Event) -> Option<String> {
+        let options = &self.options;
+        if let Some((timestamp, last)) = state.last {
+            let hours = (event.timestamp - timestamp).num_milliseconds().max(0) as f64 / 3_600_000.;
+            if let Some(rate) = options.max_gravity_rate {
+                let allowed = options.gravity_noise + rate * hours / 24.;
+                if (event.gravity - last.gravity).abs() > allowed {
+                    return Some(format!(
+                        "gravity changed from {} to {} in {:.2} hours",
+                        last.gravity, event.gravity, hours
+                    ));
+                }
+            }
+            if let Some(rate) = options.max_temperature_rate {
+                let allowed = options.temperature_noise + rate * hours;
+                if (event.temperature - last.temperature).abs() > allowed {
+                    return Some(format!(
+                        "temperature changed from {} to {} in {:.2} hours",
+                        last.temperature, event.temperature, hours
+                    ));
+                }
+            }
+        }
+        if let Some(window) = options.mad_window {
+            if state.window.len() == window {
+                let gravities = state.window.iter().map(|r| r.gravity).collect::<Vec<_>>();
+                // Tilts report whole thousandths, so a window of identical
+                // readings mustn't make every change an outlier
+                if let Some(score) = mad_score(&gravities, event.gravity, 0.001) {
+                    if score > options.mad_threshold {
+                        return Some(format!(
+                            "gravity {} is {:.1} deviations from the median",
+                            event.gravity, score
+                        ));
+                    }
+                }
+                let temperatures = state
+                    .window
+                    .iter()
+                    .map(|r| r.temperature)
+                    .collect::<Vec<_>>();
+                if let Some(score) = mad_score(&temperatures, event.temperature, 1.) {
+                    if score > options.mad_threshold {
+                        return Some(format!(
+                            "temperature {} is {:.1} deviations from the median",
+                            event.temperature, score
+                        ));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Whether the held back readings agree with each other, which means
+    /// the device has really changed, rather than glitched.
+    fn confirmed(&self, held: &[Reading]) -> bool {
+        if self.options.confirm == 0 || held.len() < self.options.confirm {
+            return false;
+        }
+        let range = |values: &mut dyn Iterator<Item = f64>| {
+            let values = values.collect::<Vec<_>>();
+            let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+            let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            max - min
+        };
+        range(&mut held.iter().map(|r| r.gravity)) <= 2. * self.options.gravity_noise
+            && range(&mut held.iter().map(|r| r.temperature)) <= 2. * self.options.temperature_noise
+    }
+}
+
+/// The modified z-score of `value` compared to `values`, with the median
+/// absolute deviation no lower than `resolution`.
+fn mad_score(values: &[f64], value: f64, resolution: f64) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    let center = median(values);
+    let mad = median(
+        &values
+            .iter()
+            .map(|v| (v - center).abs())
+            .collect::<Vec<_>>(),
+    );
+    Some(0.6745 * (value - center).abs() / mad.max(resolution))
+}
+
+impl Processor for Outliers {
+    fn process(&mut self, mut event: Event) -> Option<Event> {
+        let mut state = self.devices.remove(&event.device()).unwrap_or_default();
+        let reading = Reading {
+            temperature: event.temperature,
+            gravity: event.gravity,
+        };
+        let accepted = match self.check(&state, &event) {
+            None => true,
+            Some(reason) => {
+                state.held.push(reading);
+                if self.confirmed(&state.held) {
+                    debug!(
+                        "Accepting {} readings in a row from {} as a real change",
+                        state.held.len(),
+                        event.device()
+                    );
+                    state.window.clear();
+                    state.window.extend(state.held.iter());
+                    true
+                } else {
+                    state.rejected += 1;
+                    debug!(
+                        "Rejected reading from {} as an outlier: {} ({} rejected so far)",
+                        event.device(),
+                        reason,
+                        state.rejected
+                    );
+                    false
+                }
+            }
+        };
+        if accepted {
+            state.held.clear();
+            state.last = Some((event.timestamp, reading));
+            if let Some(window) = self.options.mad_window {
+                if state.window.len() >= window {
+                    state.window.pop_front();
+                }
+                state.window.push_back(reading);
+            }
+        }
+        event.outliers = state.rejected;
+        event.outlier = !accepted;
+        self.devices.insert(event.device(), state);
+
+        if accepted || self.options.action == Action::Tag {
+            Some(event)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::event::Color;
+    use chrono::Duration;
+
+    fn options() -> OutlierOptions {
+        toml::from_str("max-gravity-rate = 0.05\nmad-window = 5").unwrap()
+    }
+
+    fn run(outliers: &mut Outliers, gravities: &[f64]) -> Vec<bool> {
+        let start = Utc::now();
+        gravities
+            .iter()
+            .enumerate()
+            .map(|(i, gravity)| {
+                let timestamp = start + Duration::minutes(i as i64);
+                let event = Event::new(Color::Red, None, timestamp, 68., *gravity);
+                outliers.process(event).is_some()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rejects_spikes() {
+        let mut outliers = Outliers::new(options());
+        let accepted = run(
+            &mut outliers,
+            &[1.050, 1.051, 1.050, 1.049, 1.050, 1.030, 1.050, 1.051],
+        );
+        assert_eq!(
+            accepted,
+            vec![true, true, true, true, true, false, true, true]
+        );
+    }
+
+    #[test]
+    fn accepts_confirmed_changes() {
+        let mut outliers = Outliers::new(options());
+        let accepted = run(&mut outliers, &[1.050, 1.050, 1.020, 1.021, 1.020, 1.020]);
+        assert_eq!(accepted, vec![true, true, false, false, true, true]);
+    }
+
+    #[test]
+    fn tags() {
+        let mut options = options();
+        options.action = Action::Tag;
+        let mut outliers = Outliers::new(options);
+        let start = Utc::now();
+        outliers.process(Event::new(Color::Red, None, start, 68., 1.050));
+        let event = outliers
+            .process(Event::new(Color::Red, None, start, 68., 1.070))
+            .unwrap();
+        assert!(event.outlier);
+        assert_eq!(event.outliers, 1);
+    }
+}
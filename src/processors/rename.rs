@@ -0,0 +1,41 @@
+use super::{Processor, ProcessorConfig, Sections};
+use crate::dispatcher::DeviceFilter;
+use crate::event::Event;
+use anyhow::Result;
+use serde::Deserialize;
+
+/// The options of the `rename` stage.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RenameOptions {
+    name: String,
+    devices: Option<DeviceFilter>,
+}
+
+impl ProcessorConfig for RenameOptions {
+    fn get_processor(&self, _sections: &Sections) -> Result<Box<dyn Processor>> {
+        Ok(Box::new(Rename {
+            options: self.clone(),
+        }))
+    }
+}
+
+/// Gives the devices matching a filter, or every device, a new name.
+#[derive(Debug)]
+pub struct Rename {
+    options: RenameOptions,
+}
+
+impl Processor for Rename {
+    fn process(&mut self, mut event: Event) -> Option<Event> {
+        let matches = self
+            .options
+            .devices
+            .as_ref()
+            .is_none_or(|devices| devices.matches(&event));
+        if matches {
+            event.name = Some(self.options.name.clone());
+        }
+        Some(event)
+    }
+}
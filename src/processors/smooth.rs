@@ -0,0 +1,337 @@
+use super::{median, Processor};
+use crate::event::{Event, Reading};
+use crate::units::round;
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
+
+/// A smoothing filter, from a `[smoothing.<name>]` section.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "filter")]
+pub enum Smoothing {
+    #[serde(rename = "moving-average")]
+    MovingAverage { window: usize },
+    #[serde(rename = "median")]
+    Median { window: usize },
+    #[serde(rename = "exponential")]
+    Exponential { alpha: f64 },
+    #[serde(rename = "kalman")]
+    Kalman {
+        #[serde(rename = "gravity-process-noise")]
+        #[serde(default = "default_gravity_process_noise")]
+        gravity_process_noise: f64,
+        #[serde(rename = "gravity-measurement-noise")]
+        #[serde(default = "default_gravity_measurement_noise")]
+        gravity_measurement_noise: f64,
+        #[serde(rename = "temperature-process-noise")]
+        #[serde(default = "default_temperature_process_noise")]
+        temperature_process_noise: f64,
+        #[serde(rename = "temperature-measurement-noise")]
+        #[serde(default = "default_temperature_measurement_noise")]
+        temperature_measurement_noise: f64,
+    },
+}
+
+fn default_gravity_process_noise() -> f64 {
+    0.0001
+}
+fn default_gravity_measurement_noise() -> f64 {
+    0.001
+}
+fn default_temperature_process_noise() -> f64 {
+    0.1
+}
+fn default_temperature_measurement_noise() -> f64 {
+    0.5
+}
+
+impl Smoothing {
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Smoothing::MovingAverage { window } | Smoothing::Median { window } => {
+                if *window == 0 {
+                    bail!("window must be at least 1");
+                }
+            }
+            Smoothing::Exponential { alpha } => {
+                if !(*alpha > 0. && *alpha <= 1.) {
+                    bail!("alpha must be more than 0 and at most 1, not {}", alpha);
+                }
+            }
+            Smoothing::Kalman {
+                gravity_process_noise,
+                gravity_measurement_noise,
+                temperature_process_noise,
+                temperature_measurement_noise,
+            } => {
+                let finite = [
+                    gravity_process_noise,
+                    gravity_measurement_noise,
+                    temperature_process_noise,
+                    temperature_measurement_noise,
+                ]
+                .iter()
+                .all(|noise| noise.is_finite());
+                if !finite || *gravity_process_noise < 0. || *temperature_process_noise < 0. {
+                    bail!("process noise must be 0 or more");
+                }
+                if !(*gravity_measurement_noise > 0. && *temperature_measurement_noise > 0.) {
+                    bail!("measurement noise must be positive");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// New filters for the temperature and the gravity of one device.
+    fn filters(&self) -> Filters {
+        match self {
+            Smoothing::MovingAverage { window } => (
+                Box::new(MovingAverage::new(*window)),
+                Box::new(MovingAverage::new(*window)),
+            ),
+            Smoothing::Median { window } => (
+                Box::new(Median::new(*window)),
+                Box::new(Median::new(*window)),
+            ),
+            Smoothing::Exponential { alpha } => (
+                Box::new(Exponential::new(*alpha)),
+                Box::new(Exponential::new(*alpha)),
+            ),
+            Smoothing::Kalman {
+                gravity_process_noise,
+                gravity_measurement_noise,
+                temperature_process_noise,
+                temperature_measurement_noise,
+            } => (
+                Box::new(Kalman::new(
+                    *temperature_process_noise,
+                    *temperature_measurement_noise,
+                )),
+                Box::new(Kalman::new(
+                    *gravity_process_noise,
+                    *gravity_measurement_noise,
+                )),
+            ),
+        }
+    }
+}
+
+trait Filter: Debug + Send {
+    /// Add a value, and get the smoothed value back.
+    fn update(&mut self, value: f64) -> f64;
+}
+
+#[derive(Debug)]
+struct MovingAverage {
+    window: usize,
+    values: VecDeque<f64>,
+}
+
+impl MovingAverage {
+    fn new(window: usize) -> MovingAverage {
+        MovingAverage {
+            window,
+            values: VecDeque::with_capacity(window),
+        }
+    }
+}
+
+impl Filter for MovingAverage {
+    fn update(&mut self, value: f64) -> f64 {
+        if self.values.len() == self.window {
+            self.values.pop_front();
+        }
+        self.values.push_back(value);
+        self.values.iter().sum::<f64>() / self.values.len() as f64
+    }
+}
+
+#[derive(Debug)]
+struct Median {
+    window: usize,
+    values: VecDeque<f64>,
+}
+
+impl Median {
+    fn new(window: usize) -> Median {
+        Median {
+            window,
+            values: VecDeque::with_capacity(window),
+        }
+    }
+}
+
+impl Filter for Median {
+    fn update(&mut self, value: f64) -> f64 {
+        if self.values.len() == self.window {
+            self.values.pop_front();
+        }
+        self.values.push_back(value);
+        median(&self.values.iter().copied().collect::<Vec<_>>())
+    }
+}
+
+#[derive(Debug)]
+struct Exponential {
+    alpha: f64,
+    value: Option<f64>,
+}
+
+impl Exponential {
+    fn new(alpha: f64) -> Exponential {
+        Exponential { alpha, value: None }
+    }
+}
+
+impl Filter for Exponential {
+    fn update(&mut self, value: f64) -> f64 {
+        let smoothed = match self.value {
+            Some(previous) => previous + self.alpha * (value - previous),
+            None => value,
+        };
+        self.value = Some(smoothed);
+        smoothed
+    }
+}
+
+/// A one dimensional Kalman filter, assuming the value stays constant
+/// apart from some random drift. The noise parameters are standard
+/// deviations, per reading.
+#[derive(Debug)]
+struct Kalman {
+    process_variance: f64,
+    measurement_variance: f64,
+    estimate: Option<(f64, f64)>, // Value and its variance
+}
+
+impl Kalman {
+    fn new(process_noise: f64, measurement_noise: f64) -> Kalman {
+        Kalman {
+            process_variance: process_noise.powi(2),
+            measurement_variance: measurement_noise.powi(2),
+            estimate: None,
+        }
+    }
+}
+
+impl Filter for Kalman {
+    fn update(&mut self, value: f64) -> f64 {
+        let (estimate, variance) = match self.estimate {
+            Some((estimate, variance)) => {
+                let variance = variance + self.process_variance;
+                let gain = variance / (variance + self.measurement_variance);
+                (estimate + gain * (value - estimate), (1. - gain) * variance)
+            }
+            None => (value, self.measurement_variance),
+        };
+        self.estimate = Some((estimate, variance));
+        estimate
+    }
+}
+
+/// Runs every configured smoothing filter for every device, and puts the
+/// results in `Event::smoothed`, keyed by the name of the filter.
+#[derive(Debug)]
+pub struct Smooth {
+    smoothing: HashMap<String, Smoothing>,
+    filters: HashMap<String, HashMap<String, Filters>>, // By device, then name
+}
+
+/// The temperature and gravity filters for one device.
+type Filters = (Box<dyn Filter>, Box<dyn Filter>);
+
+impl Smooth {
+    pub fn new(smoothing: HashMap<String, Smoothing>) -> Smooth {
+        Smooth {
+            smoothing,
+            filters: HashMap::new(),
+        }
+    }
+}
+
+impl Processor for Smooth {
+    fn process(&mut self, mut event: Event) -> Option<Event> {
+        let smoothing = &self.smoothing;
+        let filters = self.filters.entry(event.device()).or_insert_with(|| {
+            smoothing
+                .iter()
+                .map(|(name, smoothing)| (name.clone(), smoothing.filters()))
+                .collect()
+        });
+        for (name, (temperature, gravity)) in filters {
+            event.smoothed.insert(
+                name.clone(),
+                Reading {
+                    temperature: round(temperature.update(event.temperature), 2),
+                    gravity: round(gravity.update(event.gravity), 4),
+                },
+            );
+        }
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(filter: &mut dyn Filter, values: &[f64]) -> Vec<f64> {
+        values.iter().map(|value| filter.update(*value)).collect()
+    }
+
+    #[test]
+    fn moving_average() {
+        let mut filter = MovingAverage::new(3);
+        assert_eq!(run(&mut filter, &[3., 6., 9., 12.]), vec![3., 4.5, 6., 9.]);
+    }
+
+    #[test]
+    fn median() {
+        let mut filter = Median::new(3);
+        assert_eq!(
+            run(&mut filter, &[1., 3., 100., 2., 2.]),
+            vec![1., 2., 3., 3., 2.]
+        );
+        // A NaN is sorted last instead of panicking
+        assert_eq!(crate::processors::median(&[1., f64::NAN, 2.]), 2.);
+    }
+
+    #[test]
+    fn exponential() {
+        let mut filter = Exponential::new(0.5);
+        assert_eq!(run(&mut filter, &[4., 8., 8.]), vec![4., 6., 7.]);
+    }
+
+    #[test]
+    fn validate() {
+        let valid = |smoothing: &str| {
+            toml::from_str::<Smoothing>(smoothing)
+                .unwrap()
+                .validate()
+                .is_ok()
+        };
+        assert!(valid("filter = \"exponential\"\nalpha = 1"));
+        assert!(!valid("filter = \"exponential\"\nalpha = 0"));
+        assert!(!valid("filter = \"exponential\"\nalpha = nan"));
+        assert!(valid("filter = \"kalman\""));
+        assert!(!valid(
+            "filter = \"kalman\"\ngravity-measurement-noise = nan"
+        ));
+        assert!(!valid(
+            "filter = \"kalman\"\ntemperature-process-noise = inf"
+        ));
+        assert!(!valid("filter = \"kalman\"\ngravity-process-noise = -1"));
+    }
+
+    #[test]
+    fn kalman() {
+        let mut filter = Kalman::new(0.0001, 0.001);
+        let values = run(&mut filter, &[1.050, 1.052, 1.048, 1.050, 1.070]);
+        assert_eq!(values[0], 1.050);
+        // A single outlier only moves the estimate part of the way
+        assert!(values[4] > 1.050 && values[4] < 1.060);
+    }
+}
@@ -0,0 +1,59 @@
+use super::Processor;
+use crate::devices::Devices;
+use crate::event::Event;
+use crate::units::{self, round, TemperatureUnit};
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TemperatureCorrection {
+    reference: Option<f64>,
+    #[serde(default)]
+    unit: TemperatureUnit,
+}
+
+impl TemperatureCorrection {
+    /// The temperature to correct to, in Fahrenheit. Defaults to the usual
+    /// hydrometer calibration temperature for the unit, 60°F or 20°C.
+    pub fn reference(&self) -> f64 {
+        match self.unit {
+            TemperatureUnit::Fahrenheit => self.reference.unwrap_or(60.),
+            TemperatureUnit::Celsius => units::celsius_to_fahrenheit(self.reference.unwrap_or(20.)),
+        }
+    }
+}
+
+/// Corrects the gravity of devices that have temperature correction
+/// configured, keeping the uncorrected value in
+/// `Event::uncorrected_gravity`.
+#[derive(Debug)]
+pub struct Correct {
+    devices: Devices,
+}
+
+impl Correct {
+    pub fn new(devices: Devices) -> Correct {
+        Correct { devices }
+    }
+}
+
+impl Processor for Correct {
+    fn process(&mut self, mut event: Event) -> Option<Event> {
+        let correction = self
+            .devices
+            .get(&event)
+            .and_then(|device| device.temperature_correction.as_ref());
+        if let Some(correction) = correction {
+            event.uncorrected_gravity = Some(event.gravity);
+            event.gravity = round(
+                units::temperature_correct(
+                    event.gravity,
+                    event.temperature,
+                    correction.reference(),
+                ),
+                4,
+            );
+        }
+        Some(event)
+    }
+}
@@ -0,0 +1,262 @@
+use super::Processor;
+use crate::alert::{Alert, AlertKind};
+use crate::event::Event;
+use crate::units::{round, TemperatureUnit};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::info;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum Condition {
+    TemperatureAbove,
+    TemperatureBelow,
+    GravityAbove,
+    GravityBelow,
+    GravityRateAbove,
+    GravityRateBelow,
+}
+
+/// One `[[alert]]` section.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AlertRule {
+    name: String,
+    condition: Condition,
+    threshold: f64,
+    #[serde(default)]
+    hysteresis: f64,
+    #[serde(with = "humantime_serde")]
+    #[serde(default)]
+    duration: Duration,
+    #[serde(default)]
+    devices: Vec<String>,
+    #[serde(rename = "temperature-unit")]
+    #[serde(default)]
+    temperature_unit: TemperatureUnit,
+}
+
+impl AlertRule {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.hysteresis < 0. {
+            bail!("hysteresis can't be negative");
+        }
+        Ok(())
+    }
+
+    /// The value the rule looks at, in the rule's unit, if it's known.
+    fn value(&self, event: &Event) -> Option<f64> {
+        match self.condition {
+            Condition::TemperatureAbove | Condition::TemperatureBelow => {
+                Some(self.temperature_unit.convert(event.temperature))
+            }
+            Condition::GravityAbove | Condition::GravityBelow => Some(event.gravity),
+            Condition::GravityRateAbove | Condition::GravityRateBelow => event.gravity_rate,
+        }
+    }
+
+    fn quantity(&self) -> &'static str {
+        match self.condition {
+            Condition::TemperatureAbove | Condition::TemperatureBelow => "temperature",
+            Condition::GravityAbove | Condition::GravityBelow => "gravity",
+            Condition::GravityRateAbove | Condition::GravityRateBelow => "gravity rate",
+        }
+    }
+
+    fn is_above(&self) -> bool {
+        matches!(
+            self.condition,
+            Condition::TemperatureAbove | Condition::GravityAbove | Condition::GravityRateAbove
+        )
+    }
+
+    /// Whether the value is past the threshold.
+    fn triggered(&self, value: f64) -> bool {
+        if self.is_above() {
+            value > self.threshold
+        } else {
+            value < self.threshold
+        }
+    }
+
+    /// Whether the value is back past the threshold by the hysteresis.
+    fn recovered(&self, value: f64) -> bool {
+        if self.is_above() {
+            value <= self.threshold - self.hysteresis
+        } else {
+            value >= self.threshold + self.hysteresis
+        }
+    }
+
+    fn applies_to(&self, event: &Event) -> bool {
+        let color: &'static str = (&event.color).into();
+        self.devices.is_empty()
+            || self.devices.iter().any(|device| {
+                device.eq_ignore_ascii_case(color)
+                    || event
+                        .mac
+                        .as_ref()
+                        .is_some_and(|mac| device.eq_ignore_ascii_case(mac))
+            })
+    }
+}
+
+#[derive(Debug, Default)]
+struct RuleState {
+    since: Option<DateTime<Utc>>, // When the value went past the threshold
+    firing: bool,
+}
+
+/// Evaluates the `[[alert]]` rules for every device. A rule alerts when
+/// its value has been past the threshold for the rule's duration, and
+/// sends a recovered alert when the value is back past the threshold by
+/// the hysteresis.
+#[derive(Debug)]
+pub struct Thresholds {
+    rules: Vec<(AlertRule, chrono::Duration)>,
+    state: HashMap<(String, usize), RuleState>,
+}
+
+impl Thresholds {
+    pub fn new(rules: Vec<AlertRule>) -> Result<Thresholds> {
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                let duration = chrono::Duration::from_std(rule.duration)
+                    .with_context(|| format!("The duration of {} is too long", rule.name))?;
+                Ok((rule, duration))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Thresholds {
+            rules,
+            state: HashMap::new(),
+        })
+    }
+}
+
+impl Processor for Thresholds {
+    fn process(&mut self, mut event: Event) -> Option<Event> {
+        for (index, (rule, duration)) in self.rules.iter().enumerate() {
+            if !rule.applies_to(&event) {
+                continue;
+            }
+            let value = match rule.value(&event) {
+                Some(value) => value,
+                None => continue,
+            };
+            let state = self.state.entry((event.device(), index)).or_default();
+            let alert = if !state.firing {
+                if !rule.triggered(value) {
+                    state.since = None;
+                    continue;
+                }
+                let since = *state.since.get_or_insert(event.timestamp);
+                if event.timestamp - since < *duration {
+                    continue;
+                }
+                state.firing = true;
+                Alert::new(
+                    AlertKind::Threshold,
+                    format!(
+                        "{}: the {} of the {} tilt is {}, {} {}",
+                        rule.name,
+                        rule.quantity(),
+                        event.device(),
+                        round(value, 4),
+                        if rule.is_above() { "above" } else { "below" },
+                        rule.threshold
+                    ),
+                    &event,
+                )
+            } else {
+                if !rule.recovered(value) {
+                    continue;
+                }
+                state.firing = false;
+                state.since = None;
+                Alert::new(
+                    AlertKind::Recovered,
+                    format!(
+                        "{} has recovered: the {} of the {} tilt is back to {}",
+                        rule.name,
+                        rule.quantity(),
+                        event.device(),
+                        round(value, 4),
+                    ),
+                    &event,
+                )
+            };
+            info!("{}", alert.message);
+            event.alerts.push(Alert {
+                rule: Some(rule.name.clone()),
+                ..alert
+            });
+        }
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::event::Color;
+
+    #[test]
+    fn thresholds() {
+        let rule: AlertRule = toml::from_str(
+            r#"name = "Too warm"
+condition = "temperature-above"
+threshold = 22
+hysteresis = 1
+duration = "10m"
+devices = ["red"]
+temperature-unit = "celsius"
+"#,
+        )
+        .unwrap();
+        let mut thresholds = Thresholds::new(vec![rule.clone()]).unwrap();
+        let start = Utc::now();
+        // °F, so 20°C, 23°C, 21.5°C and 20.5°C
+        let temperatures = [68., 73.4, 73.4, 73.4, 70.7, 73.4, 68.9];
+        let alerts = temperatures
+            .iter()
+            .enumerate()
+            .map(|(i, temperature)| {
+                let timestamp = start + chrono::Duration::minutes(5 * i as i64);
+                let event = Event::new(Color::Red, None, timestamp, *temperature, 1.05);
+                let alerts = thresholds.process(event).unwrap().alerts;
+                alerts.iter().map(|alert| alert.kind).collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            alerts,
+            vec![
+                vec![],
+                vec![],
+                vec![],
+                // Too warm for 10 minutes
+                vec![AlertKind::Threshold],
+                // Cooler, but still within the hysteresis
+                vec![],
+                vec![],
+                vec![AlertKind::Recovered],
+            ]
+        );
+        let event = Event::new(Color::Blue, None, start, 100., 1.05);
+        let event = thresholds.process(event).unwrap();
+        assert!(event.alerts.is_empty());
+
+        let rule = AlertRule {
+            duration: Duration::from_secs(u64::MAX),
+            ..rule
+        };
+        assert!(Thresholds::new(vec![rule]).is_err());
+    }
+}
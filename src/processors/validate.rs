@@ -0,0 +1,131 @@
+use super::{Processor, ProcessorConfig, Sections};
+use crate::event::Event;
+use crate::units::TemperatureUnit;
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use tracing::debug;
+
+/// The options of the `validate` stage.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ValidateOptions {
+    #[serde(rename = "min-gravity")]
+    #[serde(default = "default_min_gravity")]
+    min_gravity: f64,
+    #[serde(rename = "max-gravity")]
+    #[serde(default = "default_max_gravity")]
+    max_gravity: f64,
+    #[serde(rename = "min-temperature")]
+    min_temperature: Option<f64>,
+    #[serde(rename = "max-temperature")]
+    max_temperature: Option<f64>,
+    #[serde(rename = "temperature-unit")]
+    #[serde(default)]
+    temperature_unit: TemperatureUnit,
+}
+
+fn default_min_gravity() -> f64 {
+    0.98
+}
+fn default_max_gravity() -> f64 {
+    1.2
+}
+impl ValidateOptions {
+    /// The lowest valid temperature, in Fahrenheit. Defaults to 0°F,
+    /// whatever the unit.
+    fn min_temperature(&self) -> f64 {
+        self.min_temperature
+            .map_or(0., |min| self.temperature_unit.to_fahrenheit(min))
+    }
+
+    /// The highest valid temperature, in Fahrenheit. Defaults to 212°F,
+    /// whatever the unit.
+    fn max_temperature(&self) -> f64 {
+        self.max_temperature
+            .map_or(212., |max| self.temperature_unit.to_fahrenheit(max))
+    }
+}
+
+impl ProcessorConfig for ValidateOptions {
+    fn get_processor(&self, _sections: &Sections) -> Result<Box<dyn Processor>> {
+        let (min_temperature, max_temperature) = (self.min_temperature(), self.max_temperature());
+        if self.min_gravity > self.max_gravity || min_temperature > max_temperature {
+            bail!("The minimum can't be more than the maximum");
+        }
+        Ok(Box::new(Validate {
+            min_gravity: self.min_gravity,
+            max_gravity: self.max_gravity,
+            min_temperature,
+            max_temperature,
+        }))
+    }
+}
+
+/// Drops readings that can't be right, whatever the device says. The
+/// temperatures are in Fahrenheit.
+#[derive(Debug)]
+pub struct Validate {
+    min_gravity: f64,
+    max_gravity: f64,
+    min_temperature: f64,
+    max_temperature: f64,
+}
+
+impl Processor for Validate {
+    fn process(&mut self, event: Event) -> Option<Event> {
+        if event.gravity < self.min_gravity
+            || event.gravity > self.max_gravity
+            || event.temperature < self.min_temperature
+            || event.temperature > self.max_temperature
+        {
+            debug!(
+                "Dropping an invalid reading from {}: {}°F, {}",
+                event.device(),
+                event.temperature,
+                event.gravity
+            );
+            return None;
+        }
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::event::Color;
+    use chrono::Utc;
+
+    #[test]
+    fn validate() -> Result<()> {
+        let options: ValidateOptions = toml::from_str(
+            r#"max-gravity = 1.1
+min-temperature = 0
+max-temperature = 40
+temperature-unit = "celsius"
+"#,
+        )?;
+        let mut validate = options.get_processor(&Sections::default())?;
+        let mut valid = |temperature, gravity| {
+            let event = Event::new(Color::Red, None, Utc::now(), temperature, gravity);
+            validate.process(event).is_some()
+        };
+        assert!(valid(68., 1.050));
+        assert!(!valid(68., 1.150));
+        assert!(!valid(68., 0.5));
+        assert!(!valid(30., 1.050));
+        assert!(!valid(110., 1.050));
+
+        // The default limits are the same whatever the unit
+        let options: ValidateOptions =
+            toml::from_str("max-temperature = 40\ntemperature-unit = \"celsius\"\n")?;
+        let mut validate = options.get_processor(&Sections::default())?;
+        let mut valid = |temperature| {
+            let event = Event::new(Color::Red, None, Utc::now(), temperature, 1.050);
+            validate.process(event).is_some()
+        };
+        assert!(valid(10.));
+        assert!(!valid(-1.));
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// What to do when a queue is full.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Overflow {
+    #[default]
+    DropOldest,
+    DropNewest,
+    Block,
+}
+
+/// What came out of a queue.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Popped<T> {
+    Item(T),
+    TimedOut,
+    Closed,
+}
+
+/// What happened to an item pushed to a queue.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Pushed {
+    Queued,
+    DroppedOldest,
+    DroppedNewest,
+}
+
+#[derive(Debug)]
+struct State<T> {
+    items: VecDeque<T>,
+    closed: bool,
+}
+
+/// A queue with room for a fixed number of items, for handing work from
+/// one thread to another.
+#[derive(Debug)]
+pub struct BoundedQueue<T> {
+    capacity: usize,
+    overflow: Overflow,
+    state: Mutex<State<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+impl<T> BoundedQueue<T> {
+    pub fn new(capacity: usize, overflow: Overflow) -> BoundedQueue<T> {
+        BoundedQueue {
+            capacity,
+            overflow,
+            state: Mutex::new(State {
+                items: VecDeque::with_capacity(capacity),
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        }
+    }
+
+    pub fn push(&self, item: T) -> Pushed {
+        let mut state = self.state.lock().unwrap();
+        let mut pushed = Pushed::Queued;
+        if state.items.len() >= self.capacity {
+            match self.overflow {
+                Overflow::DropOldest => {
+                    state.items.pop_front();
+                    pushed = Pushed::DroppedOldest;
+                }
+                Overflow::DropNewest => return Pushed::DroppedNewest,
+                Overflow::Block => {
+                    state = self
+                        .not_full
+                        .wait_while(state, |state| {
+                            state.items.len() >= self.capacity && !state.closed
+                        })
+                        .unwrap();
+                }
+            }
+        }
+        state.items.push_back(item);
+        self.not_empty.notify_one();
+        pushed
+    }
+
+    /// The next item, waiting for one if the queue is empty. Returns
+    /// `None` once the queue is closed and empty.
+    pub fn pop(&self) -> Option<T> {
+        let state = self.state.lock().unwrap();
+        let mut state = self
+            .not_empty
+            .wait_while(state, |state| state.items.is_empty() && !state.closed)
+            .unwrap();
+        let item = state.items.pop_front();
+        self.not_full.notify_one();
+        item
+    }
+
+    /// Like `pop`, but gives up after `timeout`.
+    pub fn pop_timeout(&self, timeout: Duration) -> Popped<T> {
+        let state = self.state.lock().unwrap();
+        let (mut state, _) = self
+            .not_empty
+            .wait_timeout_while(state, timeout, |state| {
+                state.items.is_empty() && !state.closed
+            })
+            .unwrap();
+        match state.items.pop_front() {
+            Some(item) => {
+                self.not_full.notify_one();
+                Popped::Item(item)
+            }
+            None if state.closed => Popped::Closed,
+            None => Popped::TimedOut,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().items.len()
+    }
+
+    /// Stop waiting for more items. Whatever is already queued can still
+    /// be popped.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn drain(queue: &BoundedQueue<i32>) -> Vec<i32> {
+        queue.close();
+        std::iter::from_fn(|| queue.pop()).collect()
+    }
+
+    #[test]
+    fn overflow() {
+        let queue = BoundedQueue::new(2, Overflow::DropOldest);
+        assert_eq!(queue.push(1), Pushed::Queued);
+        assert_eq!(queue.push(2), Pushed::Queued);
+        assert_eq!(queue.push(3), Pushed::DroppedOldest);
+        assert_eq!(drain(&queue), vec![2, 3]);
+
+        let queue = BoundedQueue::new(2, Overflow::DropNewest);
+        queue.push(1);
+        queue.push(2);
+        assert_eq!(queue.push(3), Pushed::DroppedNewest);
+        assert_eq!(drain(&queue), vec![1, 2]);
+
+        let queue = Arc::new(BoundedQueue::new(2, Overflow::Block));
+        let consumer = {
+            let queue = queue.clone();
+            thread::spawn(move || std::iter::from_fn(|| queue.pop()).collect::<Vec<_>>())
+        };
+        for i in 0..100 {
+            assert_eq!(queue.push(i), Pushed::Queued);
+        }
+        queue.close();
+        assert_eq!(consumer.join().unwrap(), (0..100).collect::<Vec<_>>());
+
+        let queue = BoundedQueue::new(2, Overflow::Block);
+        queue.push(1);
+        let timeout = Duration::from_millis(10);
+        assert_eq!(queue.pop_timeout(timeout), Popped::Item(1));
+        assert_eq!(queue.pop_timeout(timeout), Popped::TimedOut);
+        queue.close();
+        assert_eq!(queue.pop_timeout(timeout), Popped::Closed);
+    }
+}
@@ -0,0 +1,66 @@
+use anyhow::Result;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often sleeping threads check whether they should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Tells the sources when tilted has been asked to stop.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    /// Stop on SIGINT and SIGTERM. If a second one arrives before tilted
+    /// has stopped, it exits straight away.
+    pub fn register_signals(&self) -> Result<()> {
+        for signal in &[SIGINT, SIGTERM] {
+            // The order matters - the first one only exits if the flag is
+            // already set
+            flag::register_conditional_shutdown(*signal, 1, self.requested.clone())?;
+            flag::register(*signal, self.requested.clone())?;
+        }
+        Ok(())
+    }
+
+    pub fn requested(&self) -> bool {
+        self.requested.load(Ordering::Relaxed)
+    }
+
+    /// Sleep for `duration`, unless asked to stop before then. Returns
+    /// whether it was.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let end = Instant::now() + duration;
+        while !self.requested() {
+            let left = end.saturating_duration_since(Instant::now());
+            if left == Duration::from_secs(0) {
+                return false;
+            }
+            std::thread::sleep(left.min(POLL_INTERVAL));
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sleep() {
+        let shutdown = Shutdown::new();
+        assert!(!shutdown.sleep(Duration::from_millis(10)));
+        let requested = shutdown.requested.clone();
+        let sleeper = std::thread::spawn(move || shutdown.sleep(Duration::from_secs(60)));
+        requested.store(true, Ordering::Relaxed);
+        assert!(sleeper.join().unwrap());
+    }
+}
@@ -0,0 +1,26 @@
+use super::{Source, SourceConfig};
+use crate::bt;
+use crate::event::Event;
+use crate::shutdown::Shutdown;
+use anyhow::Result;
+use serde::Deserialize;
+use std::sync::mpsc::Sender;
+
+#[derive(Debug)]
+pub struct Bluetooth {}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct BluetoothOptions {}
+
+impl SourceConfig for BluetoothOptions {
+    fn get_source(&self) -> Result<Box<dyn Source>> {
+        Ok(Box::new(Bluetooth {}))
+    }
+}
+
+impl Source for Bluetooth {
+    fn run(&self, sender: Sender<Event>, shutdown: &Shutdown) -> Result<()> {
+        bt::run(&sender, shutdown)
+    }
+}
@@ -0,0 +1,89 @@
+pub mod bluetooth;
+pub mod simulator;
+
+use super::event::Event;
+use crate::shutdown::Shutdown;
+use anyhow::Result;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use tracing::error;
+
+trait SourceConfig {
+    fn get_source(&self) -> Result<Box<dyn Source>>;
+}
+
+pub trait Source: Debug + Send {
+    /// Produce events until the source is exhausted, fails, nobody is
+    /// listening anymore, or tilted is shutting down.
+    fn run(&self, sender: Sender<Event>, shutdown: &Shutdown) -> Result<()>;
+}
+
+pub fn init(config: &HashMap<String, Sources>) -> Result<Vec<Box<dyn Source>>> {
+    let mut result: Vec<Box<dyn Source>> = vec![];
+
+    for module in config.values() {
+        match module {
+            Sources::Bluetooth(module) => module.get_source().map(|m| result.push(m))?,
+            Sources::Simulator(module) => module.get_source().map(|m| result.push(m))?,
+        }
+    }
+    Ok(result)
+}
+
+/// Run each source on its own thread. The receiver gets the events from
+/// all of them, and stops when all sources have stopped.
+pub fn spawn(
+    sources: Vec<Box<dyn Source>>,
+    shutdown: &Shutdown,
+) -> (Receiver<Event>, Vec<JoinHandle<Result<()>>>) {
+    let (sender, receiver) = channel();
+    let handles = sources
+        .into_iter()
+        .map(|source| {
+            let sender = sender.clone();
+            let shutdown = shutdown.clone();
+            thread::spawn(move || {
+                source.run(sender, &shutdown).map_err(|e| {
+                    error!("Source {:?} stopped: {}", source, e);
+                    e
+                })
+            })
+        })
+        .collect::<Vec<_>>();
+    (receiver, handles)
+}
+
+/// Wait for the sources to stop, returning the first error any of them
+/// stopped with.
+pub fn join(handles: Vec<JoinHandle<Result<()>>>) -> Result<()> {
+    for handle in handles {
+        handle
+            .join()
+            .map_err(|_| anyhow::anyhow!("Source thread panicked"))??;
+    }
+    Ok(())
+}
+
+/// Used when the config doesn't have a `[source]` section, so configs
+/// written before sources were pluggable keep scanning bluetooth.
+pub fn default_sources() -> HashMap<String, Sources> {
+    let mut sources = HashMap::new();
+    sources.insert(
+        "bluetooth".to_string(),
+        Sources::Bluetooth(crate::sources::bluetooth::BluetoothOptions {}),
+    );
+    sources
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "source")]
+pub enum Sources {
+    #[serde(rename = "bluetooth")]
+    Bluetooth(crate::sources::bluetooth::BluetoothOptions),
+    #[serde(rename = "simulator")]
+    Simulator(crate::sources::simulator::SimulatorOptions),
+}
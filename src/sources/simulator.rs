@@ -0,0 +1,286 @@
+use super::{Source, SourceConfig};
+use crate::event::{Color, Event};
+use crate::shutdown::Shutdown;
+use anyhow::Result;
+use chrono::Utc;
+use rand::Rng;
+use serde::Deserialize;
+use std::{
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug)]
+pub struct Simulator {
+    colors: Vec<Color>,
+    og: f64,
+    fg: f64,
+    lag: Duration,
+    duration: Duration,
+    temperature: f64,
+    temperature_profile: Vec<TemperaturePoint>,
+    gravity_noise: f64,
+    temperature_noise: f64,
+    dropout: f64,
+    speedup: f64,
+    interval: Duration,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TemperaturePoint {
+    #[serde(with = "humantime_serde")]
+    at: Duration,
+    temperature: f64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SimulatorOptions {
+    #[serde(default = "default_colors")]
+    colors: Vec<Color>,
+    #[serde(default = "default_og")]
+    og: f64,
+    #[serde(default = "default_fg")]
+    fg: f64,
+    #[serde(with = "humantime_serde")]
+    #[serde(default = "default_lag")]
+    lag: Duration,
+    #[serde(with = "humantime_serde")]
+    #[serde(default = "default_duration")]
+    duration: Duration,
+    #[serde(default = "default_temperature")]
+    temperature: f64,
+    #[serde(rename = "temperature-profile")]
+    #[serde(default)]
+    temperature_profile: Vec<TemperaturePoint>,
+    #[serde(rename = "gravity-noise")]
+    #[serde(default = "default_gravity_noise")]
+    gravity_noise: f64,
+    #[serde(rename = "temperature-noise")]
+    #[serde(default = "default_temperature_noise")]
+    temperature_noise: f64,
+    #[serde(default)]
+    dropout: f64,
+    #[serde(default = "default_speedup")]
+    speedup: f64,
+    #[serde(with = "humantime_serde")]
+    #[serde(default = "default_interval")]
+    interval: Duration,
+}
+
+fn default_colors() -> Vec<Color> {
+    vec![Color::Red]
+}
+fn default_og() -> f64 {
+    1.050
+}
+fn default_fg() -> f64 {
+    1.010
+}
+fn default_lag() -> Duration {
+    Duration::from_secs(12 * 60 * 60)
+}
+fn default_duration() -> Duration {
+    Duration::from_secs(7 * 24 * 60 * 60)
+}
+fn default_temperature() -> f64 {
+    68.
+}
+fn default_gravity_noise() -> f64 {
+    0.001
+}
+fn default_temperature_noise() -> f64 {
+    0.5
+}
+fn default_speedup() -> f64 {
+    1.
+}
+fn default_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+impl SourceConfig for SimulatorOptions {
+    fn get_source(&self) -> Result<Box<dyn Source>> {
+        if !(0. ..=1.).contains(&self.dropout) {
+            anyhow::bail!("dropout must be between 0 and 1, not {}", self.dropout);
+        }
+        if !(self.speedup > 0. && self.speedup.is_finite()) {
+            anyhow::bail!("speedup must be a positive number, not {}", self.speedup);
+        }
+        if self.interval.is_zero() {
+            anyhow::bail!("interval can't be 0");
+        }
+        for (name, noise) in [
+            ("gravity-noise", self.gravity_noise),
+            ("temperature-noise", self.temperature_noise),
+        ] {
+            if !(noise >= 0. && noise.is_finite()) {
+                anyhow::bail!("{} must be 0 or more, not {}", name, noise);
+            }
+        }
+        let mut temperature_profile = self.temperature_profile.clone();
+        temperature_profile.sort_by_key(|point| point.at);
+        Ok(Box::new(Simulator {
+            colors: self.colors.clone(),
+            og: self.og,
+            fg: self.fg,
+            lag: self.lag,
+            duration: self.duration,
+            temperature: self.temperature,
+            temperature_profile,
+            gravity_noise: self.gravity_noise,
+            temperature_noise: self.temperature_noise,
+            dropout: self.dropout,
+            speedup: self.speedup,
+            interval: self.interval,
+        }))
+    }
+}
+
+impl Simulator {
+    /// The noiseless gravity at `elapsed` simulated time. Fermentation
+    /// follows a logistic curve - slow to start, fast in the middle,
+    /// slowly tapering off towards FG.
+    fn gravity(&self, elapsed: Duration) -> f64 {
+        if elapsed <= self.lag {
+            return self.og;
+        }
+        let progress = ((elapsed - self.lag).as_secs_f64() / self.duration.as_secs_f64()).min(1.);
+        let logistic = |x: f64| 1. / (1. + (-10. * (x - 0.5)).exp());
+        let done = (logistic(progress) - logistic(0.)) / (logistic(1.) - logistic(0.));
+        self.og - (self.og - self.fg) * done
+    }
+
+    /// The noiseless temperature at `elapsed` simulated time, interpolated
+    /// linearly between the points of the temperature profile.
+    fn temperature(&self, elapsed: Duration) -> f64 {
+        let mut previous = (Duration::from_secs(0), self.temperature);
+        for point in &self.temperature_profile {
+            if elapsed < point.at {
+                let span = (point.at - previous.0).as_secs_f64();
+                let progress = (elapsed - previous.0).as_secs_f64() / span;
+                return previous.1 + (point.temperature - previous.1) * progress;
+            }
+            previous = (point.at, point.temperature);
+        }
+        previous.1
+    }
+}
+
+/// Normally distributed noise with the given standard deviation, using the
+/// Box-Muller transform.
+fn noise(rng: &mut impl Rng, stddev: f64) -> f64 {
+    let u1: f64 = 1. - rng.gen::<f64>();
+    let u2: f64 = rng.gen();
+    stddev * (-2. * u1.ln()).sqrt() * (2. * std::f64::consts::PI * u2).cos()
+}
+
+impl Source for Simulator {
+    fn run(&self, sender: Sender<Event>, shutdown: &Shutdown) -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let start = Instant::now();
+        let start_time = Utc::now();
+        loop {
+            let elapsed =
+                Duration::try_from_secs_f64(start.elapsed().as_secs_f64() * self.speedup)?;
+            // Timestamps follow the simulated clock, so anything that looks
+            // at how readings change over time sees the sped up fermentation
+            let timestamp = start_time + chrono::Duration::from_std(elapsed)?;
+            for color in &self.colors {
+                if rng.gen::<f64>() < self.dropout {
+                    continue;
+                }
+                let temperature =
+                    self.temperature(elapsed) + noise(&mut rng, self.temperature_noise);
+                let gravity = self.gravity(elapsed) + noise(&mut rng, self.gravity_noise);
+                // Round like a real tilt, which reports whole degrees and
+                // thousandths of gravity points
+                let event = Event::new(
+                    *color,
+                    None,
+                    timestamp,
+                    temperature.round(),
+                    (gravity * 1000.).round() / 1000.,
+                );
+                if sender.send(event).is_err() {
+                    return Ok(());
+                }
+            }
+            if shutdown.sleep(self.interval) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn simulator() -> Simulator {
+        Simulator {
+            colors: vec![Color::Red],
+            og: 1.050,
+            fg: 1.010,
+            lag: Duration::from_secs(60),
+            duration: Duration::from_secs(1000),
+            temperature: 60.,
+            temperature_profile: vec![TemperaturePoint {
+                at: Duration::from_secs(100),
+                temperature: 70.,
+            }],
+            gravity_noise: 0.,
+            temperature_noise: 0.,
+            dropout: 0.,
+            speedup: 1.,
+            interval: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn gravity_curve() {
+        let sim = simulator();
+        assert_eq!(sim.gravity(Duration::from_secs(30)), 1.050);
+        assert!((sim.gravity(Duration::from_secs(60 + 500)) - 1.030).abs() < 1e-9);
+        assert!((sim.gravity(Duration::from_secs(60 + 1000)) - 1.010).abs() < 1e-9);
+        assert!((sim.gravity(Duration::from_secs(100_000)) - 1.010).abs() < 1e-9);
+    }
+
+    #[test]
+    fn temperature_profile() {
+        let sim = simulator();
+        assert_eq!(sim.temperature(Duration::from_secs(0)), 60.);
+        assert_eq!(sim.temperature(Duration::from_secs(50)), 65.);
+        assert_eq!(sim.temperature(Duration::from_secs(500)), 70.);
+    }
+
+    #[test]
+    fn options() {
+        let error = |options: &str| {
+            let options: SimulatorOptions = toml::from_str(options).unwrap();
+            options.get_source().err().map(|e| e.to_string())
+        };
+        assert_eq!(error(""), None);
+        assert_eq!(
+            error("interval = \"0s\""),
+            Some("interval can't be 0".to_string())
+        );
+        assert_eq!(
+            error("gravity-noise = -0.001"),
+            Some("gravity-noise must be 0 or more, not -0.001".to_string())
+        );
+        assert_eq!(
+            error("temperature-noise = nan"),
+            Some("temperature-noise must be 0 or more, not NaN".to_string())
+        );
+        assert_eq!(
+            error("speedup = nan"),
+            Some("speedup must be a positive number, not NaN".to_string())
+        );
+        assert_eq!(
+            error("speedup = inf"),
+            Some("speedup must be a positive number, not inf".to_string())
+        );
+    }
+}
@@ -0,0 +1,332 @@
+use crate::alert::{Alert, AlertKind};
+use crate::event::Event;
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::warn;
+
+/// The `retry` option of an emitter.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RetryOptions {
+    #[serde(rename = "initial-backoff")]
+    #[serde(with = "humantime_serde")]
+    #[serde(default = "default_initial_backoff")]
+    pub initial_backoff: Duration,
+    #[serde(rename = "max-backoff")]
+    #[serde(with = "humantime_serde")]
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: Duration,
+    pub spool: Option<PathBuf>,
+    #[serde(rename = "max-size")]
+    #[serde(default = "default_max_size")]
+    pub max_size: usize,
+    #[serde(rename = "max-age")]
+    #[serde(with = "humantime_serde")]
+    #[serde(default = "default_max_age")]
+    pub max_age: Duration,
+}
+
+fn default_initial_backoff() -> Duration {
+    Duration::from_secs(1)
+}
+fn default_max_backoff() -> Duration {
+    Duration::from_secs(5 * 60)
+}
+fn default_max_size() -> usize {
+    50_000_000
+}
+fn default_max_age() -> Duration {
+    Duration::from_secs(7 * 24 * 60 * 60)
+}
+
+/// Something for an emitter to send, as it's stored in a spool.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Entry {
+    Reading {
+        event: Event,
+    },
+    Alert {
+        kind: AlertKind,
+        message: String,
+        rule: Option<String>,
+        event: Event,
+    },
+}
+
+impl Entry {
+    pub fn alert(alert: &Alert) -> Entry {
+        Entry::Alert {
+            kind: alert.kind,
+            message: alert.message.clone(),
+            rule: alert.rule.clone(),
+            event: alert.event.clone(),
+        }
+    }
+
+    pub fn event(&self) -> &Event {
+        match self {
+            Entry::Reading { event } | Entry::Alert { event, .. } => event,
+        }
+    }
+}
+
+/// Entries waiting to be sent, oldest first, kept in memory and, if there
+/// is a file, on disk as one JSON object per line. Entries are only ever
+/// removed from the front, so instead of rewriting the file every time,
+/// how far into it the entries still waiting start is kept next to it,
+/// and the file is only rewritten once that's at least half of it.
+#[derive(Debug)]
+pub struct Spool {
+    path: Option<PathBuf>,
+    max_size: usize,
+    max_age: chrono::Duration,
+    entries: VecDeque<(Entry, usize)>, // Along with their size in the file
+    size: usize,
+    head: usize, // Bytes at the start of the file that have been removed
+}
+
+impl Spool {
+    pub fn open(options: &RetryOptions) -> Result<Spool> {
+        let mut spool = Spool {
+            path: options.spool.clone(),
+            max_size: options.max_size,
+            max_age: chrono::Duration::from_std(options.max_age)?,
+            entries: VecDeque::new(),
+            size: 0,
+            head: 0,
+        };
+        let path = match &spool.path {
+            Some(path) => path.clone(),
+            None => return Ok(spool),
+        };
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(spool),
+            Err(e) => return Err(e).with_context(|| format!("Couldn't open {}", path.display())),
+        };
+        let head = read_head(&head_path(&path));
+        let (mut position, mut invalid) = (0, false);
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let size = line.len() + 1;
+            position += size;
+            if position <= head {
+                spool.head = position;
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(entry) => {
+                    spool.size += size;
+                    spool.entries.push_back((entry, size));
+                }
+                Err(e) => {
+                    warn!("Skipping invalid entry in {}: {}", path.display(), e);
+                    invalid = true;
+                }
+            }
+        }
+        spool.expire();
+        // The sizes of the entries have to add up to the file for the head
+        // to be right, which they don't with invalid lines in between
+        if invalid {
+            spool.compact()?;
+        } else {
+            spool.maybe_compact()?;
+        }
+        Ok(spool)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn is_persistent(&self) -> bool {
+        self.path.is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn push(&mut self, entry: Entry) -> Result<()> {
+        let line = serde_json::to_string(&entry)?;
+        if let Some(path) = &self.path {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", line)?;
+        }
+        self.size += line.len() + 1;
+        self.entries.push_back((entry, line.len() + 1));
+        while self.size > self.max_size && self.entries.len() > 1 {
+            warn!("The spool is full, dropping the oldest entry");
+            self.remove();
+        }
+        self.expire();
+        self.maybe_compact()
+    }
+
+    /// The oldest entry, after dropping any that are too old to send.
+    pub fn front(&mut self) -> Option<&Entry> {
+        self.expire();
+        self.entries.front().map(|(entry, _)| entry)
+    }
+
+    /// Remove the oldest entry, once it's been sent.
+    pub fn pop(&mut self) -> Result<()> {
+        self.remove();
+        self.maybe_compact()
+    }
+
+    fn remove(&mut self) {
+        if let Some((_, size)) = self.entries.pop_front() {
+            self.size -= size;
+            self.head += size;
+        }
+    }
+
+    fn expire(&mut self) {
+        let oldest = Utc::now() - self.max_age;
+        while let Some((entry, _)) = self.entries.front() {
+            if entry.event().timestamp >= oldest {
+                break;
+            }
+            warn!(
+                "Dropping an entry from {} from the spool, since it's too old",
+                entry.event().timestamp
+            );
+            self.remove();
+        }
+    }
+
+    /// Rewrite the file once at least half of it has been removed, and
+    /// otherwise just remember where what's left starts.
+    fn maybe_compact(&mut self) -> Result<()> {
+        if self.head > 0 && self.head >= self.size {
+            return self.compact();
+        }
+        if let Some(path) = &self.path {
+            fs::write(head_path(path), self.head.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite the file with only the entries that are left.
+    fn compact(&mut self) -> Result<()> {
+        if let Some(path) = &self.path {
+            let temporary = path.with_extension("tmp");
+            let mut file = File::create(&temporary)?;
+            for (entry, _) in &self.entries {
+                writeln!(file, "{}", serde_json::to_string(entry)?)?;
+            }
+            file.sync_all()?;
+            // If it crashes in between, the old file is read from the
+            // start, and what was removed is sent again rather than lost
+            fs::write(head_path(path), "0")?;
+            fs::rename(&temporary, path)?;
+        }
+        self.head = 0;
+        Ok(())
+    }
+}
+
+/// Where the head of a spool file is kept.
+fn head_path(path: &Path) -> PathBuf {
+    path.with_extension("head")
+}
+
+/// The head of a spool file. If it's missing or broken, everything in the
+/// file is sent again.
+fn read_head(path: &Path) -> usize {
+    match fs::read_to_string(path) {
+        Ok(head) => head.trim().parse().unwrap_or_else(|e| {
+            warn!("Ignoring invalid spool head in {}: {}", path.display(), e);
+            0
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => {
+            warn!("Couldn't read {}: {}", path.display(), e);
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::event::Color;
+
+    fn options(spool: PathBuf) -> RetryOptions {
+        toml::from_str(&format!(
+            "spool = \"{}\"\nmax-size = 2000\nmax-age = \"1d\"\n",
+            spool.display()
+        ))
+        .unwrap()
+    }
+
+    fn reading(minutes: i64, gravity: f64) -> Entry {
+        let timestamp = Utc::now() - chrono::Duration::minutes(minutes);
+        Entry::Reading {
+            event: Event::new(Color::Red, None, timestamp, 68., gravity),
+        }
+    }
+
+    fn gravities(spool: &Spool) -> Vec<f64> {
+        spool
+            .entries
+            .iter()
+            .map(|(entry, _)| entry.event().gravity)
+            .collect()
+    }
+
+    #[test]
+    fn spool() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let options = options(directory.path().join("spool.jsonl"));
+        let mut spool = Spool::open(&options)?;
+        spool.push(reading(2 * 24 * 60, 1.050))?;
+        assert!(spool.is_empty());
+        spool.push(reading(3, 1.049))?;
+        spool.push(reading(2, 1.048))?;
+        spool.push(reading(1, 1.047))?;
+        assert_eq!(spool.front().unwrap().event().gravity, 1.049);
+        spool.pop()?;
+
+        // It survives a restart, without what's been sent. The file isn't
+        // rewritten until at least half of it has been sent.
+        let path = options.spool.clone().unwrap();
+        let length = fs::metadata(&path)?.len();
+        let mut spool = Spool::open(&options)?;
+        assert_eq!(gravities(&spool), vec![1.048, 1.047]);
+        assert_eq!(fs::metadata(&path)?.len(), length);
+        spool.pop()?;
+        assert!(fs::metadata(&path)?.len() < length);
+        let mut spool = Spool::open(&options)?;
+        assert_eq!(gravities(&spool), vec![1.047]);
+        assert_eq!(spool.head, 0);
+
+        // A broken head means everything is sent again
+        spool.push(reading(0, 1.046))?;
+        spool.push(reading(0, 1.045))?;
+        spool.pop()?;
+        fs::write(head_path(&path), "nonsense")?;
+        let mut spool = Spool::open(&options)?;
+        assert_eq!(gravities(&spool), vec![1.047, 1.046, 1.045]);
+        spool.pop()?;
+        spool.pop()?;
+
+        // Each entry is a few hundred bytes, so they don't all fit
+        for i in 0..10 {
+            spool.push(reading(0, f64::from(1040 - i) / 1000.))?;
+        }
+        assert!(spool.size <= 2000);
+        assert!(spool.len() < 12);
+        assert_eq!(*gravities(&spool).last().unwrap(), 1.031);
+        Ok(())
+    }
+}
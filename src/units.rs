@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TemperatureUnit {
+    #[default]
+    Fahrenheit,
+    Celsius,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum GravityUnit {
+    #[default]
+    Sg,
+    Plato,
+    Brix,
+}
+
+impl TemperatureUnit {
+    /// Convert a temperature in Fahrenheit to this unit.
+    pub fn convert(self, fahrenheit: f64) -> f64 {
+        match self {
+            TemperatureUnit::Fahrenheit => fahrenheit,
+            TemperatureUnit::Celsius => fahrenheit_to_celsius(fahrenheit),
+        }
+    }
+
+    /// Convert a temperature in this unit to Fahrenheit.
+    pub fn to_fahrenheit(self, temperature: f64) -> f64 {
+        match self {
+            TemperatureUnit::Fahrenheit => temperature,
+            TemperatureUnit::Celsius => celsius_to_fahrenheit(temperature),
+        }
+    }
+}
+
+impl GravityUnit {
+    /// Convert a specific gravity to this unit.
+    pub fn convert(self, sg: f64) -> f64 {
+        match self {
+            GravityUnit::Sg => sg,
+            GravityUnit::Plato => sg_to_plato(sg),
+            GravityUnit::Brix => sg_to_brix(sg),
+        }
+    }
+}
+
+pub fn fahrenheit_to_celsius(fahrenheit: f64) -> f64 {
+    (fahrenheit - 32.) * 5. / 9.
+}
+
+pub fn celsius_to_fahrenheit(celsius: f64) -> f64 {
+    celsius * 9. / 5. + 32.
+}
+
+/// Conversions produce a long tail of meaningless decimals, so round them
+/// off before they're shown to anyone.
+pub fn round(value: f64, decimals: i32) -> f64 {
+    let factor = 10_f64.powi(decimals);
+    (value * factor).round() / factor
+}
+
+/// Adjust a hydrometer reading taken at `temperature` to what it would
+/// have been at `reference`, both in Fahrenheit, using the usual
+/// hydrometer correction polynomial.
+pub fn temperature_correct(sg: f64, temperature: f64, reference: f64) -> f64 {
+    let density = |t: f64| {
+        1.00130346 - 0.000134722124 * t + 0.00000204052596 * t.powi(2)
+            - 0.00000000232820948 * t.powi(3)
+    };
+    sg * density(temperature) / density(reference)
+}
+
+// The polynomials most brewing calculators use
+pub fn sg_to_plato(sg: f64) -> f64 {
+    -616.868 + 1111.14 * sg - 630.272 * sg.powi(2) + 135.997 * sg.powi(3)
+}
+
+pub fn sg_to_brix(sg: f64) -> f64 {
+    ((182.4601 * sg - 775.6821) * sg + 1262.7794) * sg - 669.5622
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        assert_eq!(fahrenheit_to_celsius(212.), 100.);
+        assert_eq!(celsius_to_fahrenheit(20.), 68.);
+        assert!(sg_to_plato(1.000).abs() < 0.01);
+        assert!((sg_to_plato(1.040) - 10.).abs() < 0.05);
+        assert!(sg_to_brix(1.000).abs() < 0.01);
+        assert!((sg_to_brix(1.040) - 10.).abs() < 0.05);
+    }
+
+    #[test]
+    fn temperature_correction() {
+        assert_eq!(temperature_correct(1.050, 60., 60.), 1.050);
+        // Warm wort is less dense, so it reads low
+        assert!((temperature_correct(1.050, 80., 60.) - 1.0525).abs() < 0.0005);
+        assert!((temperature_correct(1.050, 50., 60.) - 1.0493).abs() < 0.0005);
+    }
+}
@@ -0,0 +1,171 @@
+use crate::alert::{Alert, AlertKind};
+use crate::event::Event;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tracing::info;
+
+/// The `[offline]` section.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct OfflineOptions {
+    #[serde(with = "humantime_serde")]
+    #[serde(default = "default_after")]
+    after: Duration,
+}
+
+fn default_after() -> Duration {
+    Duration::from_secs(30 * 60)
+}
+
+/// When a device was last heard from.
+#[derive(Debug, Clone)]
+pub struct DeviceStatus {
+    pub last: Event, // The last reading from the device
+    pub last_seen: DateTime<Utc>,
+}
+
+impl DeviceStatus {
+    pub fn age(&self, now: &DateTime<Utc>) -> Duration {
+        (*now - self.last_seen).to_std().unwrap_or_default()
+    }
+}
+
+#[derive(Debug)]
+struct Seen {
+    last: Event,
+    at: DateTime<Utc>,
+    online: bool,
+}
+
+/// Keeps track of when every device was last heard from, by the wall
+/// clock rather than the timestamps of the readings. If offline
+/// detection is configured, it alerts when a device has been silent for
+/// too long, and when it comes back.
+#[derive(Debug)]
+pub struct Watchdog {
+    after: Option<chrono::Duration>,
+    devices: BTreeMap<String, Seen>,
+}
+
+impl Watchdog {
+    pub fn new(options: Option<OfflineOptions>) -> Result<Watchdog> {
+        let after = options
+            .map(|options| chrono::Duration::from_std(options.after))
+            .transpose()
+            .context("after is too long")?;
+        Ok(Watchdog {
+            after,
+            devices: BTreeMap::new(),
+        })
+    }
+
+    /// Record a reading. Returns an alert if the device was offline.
+    pub fn seen(&mut self, event: &Event, now: DateTime<Utc>) -> Option<Alert> {
+        let seen = Seen {
+            last: event.clone(),
+            at: now,
+            online: true,
+        };
+        let previous = self.devices.insert(event.device(), seen)?;
+        if previous.online {
+            return None;
+        }
+        let alert = Alert::new(
+            AlertKind::Online,
+            format!(
+                "The {} tilt is back online, after {} silent",
+                event.device(),
+                humantime::format_duration(to_seconds(now - previous.at))
+            ),
+            event,
+        );
+        info!("{}", alert.message);
+        Some(alert)
+    }
+
+    /// Alerts for the devices that have gone offline since the last check.
+    pub fn check(&mut self, now: DateTime<Utc>) -> Vec<Alert> {
+        let after = match self.after {
+            Some(after) => after,
+            None => return vec![],
+        };
+        let mut alerts = vec![];
+        for (device, seen) in &mut self.devices {
+            if !seen.online || now - seen.at < after {
+                continue;
+            }
+            seen.online = false;
+            let alert = Alert::new(
+                AlertKind::Offline,
+                format!(
+                    "The {} tilt is offline, it was last seen {} ago",
+                    device,
+                    humantime::format_duration(to_seconds(now - seen.at))
+                ),
+                &seen.last,
+            );
+            info!("{}", alert.message);
+            alerts.push(alert);
+        }
+        alerts
+    }
+
+    pub fn status(&self) -> Vec<DeviceStatus> {
+        self.devices
+            .values()
+            .map(|seen| DeviceStatus {
+                last: seen.last.clone(),
+                last_seen: seen.at,
+            })
+            .collect()
+    }
+}
+
+/// Whole seconds, which is precise enough for people to read.
+fn to_seconds(duration: chrono::Duration) -> Duration {
+    Duration::from_secs(duration.num_seconds().max(0) as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::event::Color;
+
+    #[test]
+    fn offline() {
+        let mut watchdog = Watchdog::new(Some(OfflineOptions {
+            after: Duration::from_secs(60 * 60),
+        }))
+        .unwrap();
+        let start = Utc::now();
+        let minutes = |minutes| start + chrono::Duration::minutes(minutes);
+        let event = Event::new(Color::Red, None, start, 68., 1.05);
+        assert!(watchdog.seen(&event, start).is_none());
+        assert!(watchdog.check(minutes(30)).is_empty());
+        let alerts = watchdog.check(minutes(60));
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, AlertKind::Offline);
+        assert_eq!(
+            alerts[0].message,
+            "The red tilt is offline, it was last seen 1h ago"
+        );
+        assert!(watchdog.check(minutes(90)).is_empty());
+        assert!(!watchdog.devices["red"].online);
+
+        let alert = watchdog.seen(&event, minutes(95)).unwrap();
+        assert_eq!(alert.kind, AlertKind::Online);
+        assert_eq!(
+            alert.message,
+            "The red tilt is back online, after 1h 35m silent"
+        );
+        assert_eq!(watchdog.status()[0].age(&minutes(100)).as_secs(), 300);
+
+        assert!(Watchdog::new(Some(OfflineOptions {
+            after: Duration::from_secs(u64::MAX),
+        }))
+        .is_err());
+    }
+}
@@ -0,0 +1,300 @@
+use crate::calibration::Calibration;
+use crate::config_edit::{device_table, table, write_config};
+use crate::event::{Color, Event, Reading};
+use crate::shutdown::Shutdown;
+use crate::sources::{self, Source};
+use crate::units::round;
+use anyhow::{bail, Context, Result};
+use clap::Clap;
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, Write},
+    sync::mpsc::Receiver,
+};
+use toml_edit::{value, Array, DocumentMut, InlineTable};
+
+#[derive(Clap, Debug)]
+pub struct CalibrateOpts {
+    /// The color of the tilt to calibrate
+    color: Color,
+    /// How many readings in a row must agree before a point is recorded
+    #[clap(long, default_value = "10")]
+    samples: usize,
+    /// How much the gravity may vary between those readings
+    #[clap(long, default_value = "0.001")]
+    tolerance: f64,
+}
+
+/// One reference point: what the tilt measured, and what it should have
+/// measured.
+struct Point {
+    measured: Reading,
+    gravity: f64,
+    temperature: Option<f64>,
+}
+
+pub fn run(
+    opts: &CalibrateOpts,
+    config_path: &str,
+    config_str: &str,
+    sources: Vec<Box<dyn Source>>,
+) -> Result<()> {
+    let color: &'static str = (&opts.color).into();
+    if opts.samples == 0 {
+        bail!("Need at least one sample per point");
+    }
+    let (receiver, _handles) = sources::spawn(sources, &Shutdown::new());
+
+    println!("Calibrating the {} tilt.", color);
+    println!("For each reference point, put the tilt in a liquid with a known gravity, such as");
+    println!("plain water (1.000) or a sugar solution measured with a refractometer.");
+    let mut points = vec![];
+    loop {
+        println!();
+        let gravity = match prompt("Actual gravity of the liquid (leave empty when done): ")? {
+            Some(gravity) => gravity,
+            None => break,
+        };
+        let temperature = prompt("Actual temperature in Fahrenheit (leave empty to skip): ")?;
+
+        // Whatever was queued up was probably measured before the tilt
+        // was moved to the new liquid
+        while receiver.try_recv().is_ok() {}
+        println!("Waiting for {} stable readings...", opts.samples);
+        let measured = wait_for_stable(&receiver, opts)?;
+        println!(
+            "The tilt measured {:.4} at {:.1}°F",
+            measured.gravity, measured.temperature
+        );
+        points.push(Point {
+            measured,
+            gravity,
+            temperature,
+        });
+    }
+    if points.is_empty() {
+        println!("No points recorded, leaving the config as it was");
+        return Ok(());
+    }
+
+    let config = write_calibration(config_str, color, &points)?;
+    write_config(config_path, &config)
+        .with_context(|| format!("Couldn't write calibration to {}", config_path))?;
+    println!(
+        "Wrote the calibration of the {} tilt to {}",
+        color, config_path
+    );
+    Ok(())
+}
+
+/// Ask for a number. Returns `None` if the answer is empty.
+fn prompt(question: &str) -> Result<Option<f64>> {
+    loop {
+        print!("{}", question);
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(None);
+        }
+        match line.parse() {
+            Ok(number) => return Ok(Some(number)),
+            Err(_) => println!("{} is not a number", line),
+        }
+    }
+}
+
+fn wait_for_stable(receiver: &Receiver<Event>, opts: &CalibrateOpts) -> Result<Reading> {
+    let mut window = VecDeque::with_capacity(opts.samples);
+    loop {
+        let event = receiver
+            .recv()
+            .context("The sources stopped before the readings were stable")?;
+        if event.color != opts.color {
+            continue;
+        }
+        println!(
+            "  {:.4} at {:.1}°F",
+            event.raw.gravity, event.raw.temperature
+        );
+        if window.len() == opts.samples {
+            window.pop_front();
+        }
+        window.push_back(event.raw);
+        if let Some(reading) = stable_average(&window, opts.samples, opts.tolerance) {
+            return Ok(reading);
+        }
+    }
+}
+
+/// The average of the readings, if there are enough of them and their
+/// gravities are within `tolerance` of each other.
+fn stable_average(readings: &VecDeque<Reading>, samples: usize, tolerance: f64) -> Option<Reading> {
+    if readings.len() < samples {
+        return None;
+    }
+    let gravities = readings.iter().map(|r| r.gravity);
+    let min = gravities.clone().fold(f64::INFINITY, f64::min);
+    let max = gravities.fold(f64::NEG_INFINITY, f64::max);
+    // A little slack, so a tolerance of 0.001 accepts readings 0.001 apart
+    if max - min > tolerance + 1e-9 {
+        return None;
+    }
+    let count = readings.len() as f64;
+    Some(Reading {
+        temperature: readings.iter().map(|r| r.temperature).sum::<f64>() / count,
+        gravity: readings.iter().map(|r| r.gravity).sum::<f64>() / count,
+    })
+}
+
+/// Put the calibration points into the `[device.<color>.calibration]`
+/// section, keeping the rest of the config as it was.
+fn write_calibration(config_str: &str, color: &str, points: &[Point]) -> Result<String> {
+    let mut config: DocumentMut = config_str.parse()?;
+    let device = device_table(&mut config, color)?;
+    let calibration = table(device, "calibration", false)?;
+    let gravity = points
+        .iter()
+        .map(|point| (point.measured.gravity, point.gravity))
+        .collect::<Vec<_>>();
+    let temperature = points
+        .iter()
+        .filter_map(|point| Some((point.measured.temperature, point.temperature?)))
+        .collect::<Vec<_>>();
+    let gravity = merge(&gravity, 4);
+    Calibration::Points {
+        points: gravity.clone(),
+    }
+    .validate()
+    .context("Invalid gravity calibration")?;
+    calibration.insert("gravity", value(points_table(&gravity)));
+    if !temperature.is_empty() {
+        let temperature = merge(&temperature, 2);
+        Calibration::Points {
+            points: temperature.clone(),
+        }
+        .validate()
+        .context("Invalid temperature calibration")?;
+        calibration.insert("temperature", value(points_table(&temperature)));
+    }
+    Ok(config.to_string())
+}
+
+/// Round the `(measured, actual)` pairs, and combine the ones that were
+/// measured the same into one point with the average actual value. The
+/// liquids are usually all at room temperature, so the temperature points
+/// tend to have the same measured value.
+fn merge(points: &[(f64, f64)], decimals: i32) -> Vec<[f64; 2]> {
+    let mut points = points
+        .iter()
+        .map(|(measured, actual)| (round(*measured, decimals), *actual))
+        .collect::<Vec<_>>();
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut merged: Vec<(f64, Vec<f64>)> = vec![];
+    for (measured, actual) in points {
+        match merged.last_mut() {
+            Some((last, actuals)) if *last == measured => actuals.push(actual),
+            _ => merged.push((measured, vec![actual])),
+        }
+    }
+    merged
+        .into_iter()
+        .map(|(measured, actuals)| {
+            let actual = actuals.iter().sum::<f64>() / actuals.len() as f64;
+            [measured, round(actual, decimals)]
+        })
+        .collect()
+}
+
+fn points_table(points: &[[f64; 2]]) -> InlineTable {
+    let mut array = Array::new();
+    for [measured, actual] in points {
+        let mut point = Array::new();
+        point.push(*measured);
+        point.push(*actual);
+        array.push(point);
+    }
+    let mut table = InlineTable::new();
+    table.insert("points", array.into());
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn reading(gravity: f64) -> Reading {
+        Reading {
+            temperature: 68.,
+            gravity,
+        }
+    }
+
+    #[test]
+    fn stable() {
+        let readings = vec![reading(1.001), reading(1.002), reading(1.001)]
+            .into_iter()
+            .collect();
+        assert!(stable_average(&readings, 4, 0.001).is_none());
+        assert!(stable_average(&readings, 3, 0.).is_none());
+        let average = stable_average(&readings, 3, 0.001).unwrap();
+        assert!((average.gravity - 1.0013333).abs() < 1e-6);
+    }
+
+    #[test]
+    fn write() -> Result<()> {
+        let config = r#"# My tilts
+[log]
+emitter = "log"
+
+[device.red]
+calibration = { gravity = { offset = 0.001 } }
+"#;
+        let points = vec![
+            Point {
+                measured: reading(1.002),
+                gravity: 1.000,
+                temperature: Some(67.),
+            },
+            Point {
+                measured: reading(1.04233333),
+                gravity: 1.040,
+                temperature: None,
+            },
+        ];
+        let written = write_calibration(config, "blue", &points)?;
+        assert!(written.starts_with("# My tilts\n[log]\n"));
+        assert!(written.contains("[device.blue.calibration]"));
+        assert!(written.contains("gravity = { points = [[1.002, 1.0], [1.0423, 1.04]] }"));
+        assert!(written.contains("temperature = { points = [[68.0, 67.0]] }"));
+        let parsed: toml::Value = toml::from_str(&written)?;
+        assert!(
+            parsed["device"]["red"]["calibration"]["gravity"]["offset"].as_float() == Some(0.001)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn same_temperature() -> Result<()> {
+        let point = |gravity, actual, temperature| Point {
+            measured: reading(gravity),
+            gravity: actual,
+            temperature: Some(temperature),
+        };
+        let points = vec![
+            point(1.002, 1.000, 67.),
+            point(1.022, 1.020, 68.),
+            point(1.0421, 1.040, 67.5),
+        ];
+        let written = write_calibration("", "red", &points)?;
+        assert!(written.contains("temperature = { points = [[68.0, 67.5]] }"));
+        let parsed: toml::Value = toml::from_str(&written)?;
+        let calibration: crate::calibration::CalibrationConfig =
+            parsed["device"]["red"]["calibration"].clone().try_into()?;
+        calibration.validate()?;
+        Ok(())
+    }
+}